@@ -3,6 +3,8 @@ use criterion::*;
 extern crate rand;
 
 use adaptive_algorithms::points::*;
+use adaptive_algorithms::polling::Detection;
+use adaptive_algorithms::steal::StealStrategy;
 
 fn bench(c: &mut Criterion) {
     let data = Point::create_random_points(5000);
@@ -24,6 +26,31 @@ fn bench(c: &mut Criterion) {
             let t = TestConfig::new(data.len(), *i, Some(s), Searcher::new(&data));
             test.push(t);
         }
+        for strategy in vec![
+            StealStrategy::Spin(64),
+            StealStrategy::Snooze(8),
+            StealStrategy::Yield(4),
+            StealStrategy::Timed(std::time::Duration::from_micros(10)),
+            StealStrategy::Adaptive,
+        ] {
+            let t = TestConfig::new(data.len(), *i, None, Searcher::new(&data))
+                .with_strategy(strategy);
+            test.push(t);
+        }
+        for multisplit in vec![true, false] {
+            let t = TestConfig::new(data.len(), *i, Some(0), Searcher::new(&data))
+                .with_multisplit(multisplit);
+            test.push(t);
+        }
+        for detection in vec![Detection::StealRequests, Detection::Polling] {
+            let t = TestConfig::new(data.len(), *i, Some(0), Searcher::new(&data))
+                .with_detection(detection);
+            test.push(t);
+        }
+        let t = TestConfig::new(data.len(), *i, Some(0), Searcher::new(&data)).with_statistics(true);
+        test.push(t);
+        let t = TestConfig::new(data.len(), *i, Some(0), IterPoints::new(&data));
+        test.push(t);
         let t = TestConfig::new(data.len(), *i, None, RayonPoints::new(&data));
         test.push(t);
         let t = TestConfig::new(data.len(), *i, None, FlatMapPoints::new(&data));
@@ -1,4 +1,5 @@
 use adaptive_algorithms::adaptive_bench::*;
+use adaptive_algorithms::polling::Detection;
 use adaptive_algorithms::scheduling::*;
 use criterion::*;
 extern crate rand;
@@ -32,6 +33,12 @@ fn bench(c: &mut Criterion) {
             let t = TestConfig::new(times.len(), *i, Some(s), Scheduling::new(&times, &procs, s));
             test.push(t);
         }
+        // how the tasks find idle threads, with the same depth
+        for detection in vec![Detection::StealRequests, Detection::Polling] {
+            let t = TestConfig::new(times.len(), *i, Some(0), Scheduling::new(&times, &procs, 8))
+                .with_detection(detection);
+            test.push(t);
+        }
         let t = TestConfig::new(
             times.len(),
             *i,
@@ -0,0 +1,34 @@
+use adaptive_algorithms::adaptive_bench::*;
+use adaptive_algorithms::sort::*;
+use criterion::*;
+extern crate rand;
+
+fn bench(c: &mut Criterion) {
+    let data: Vec<u64> = std::iter::repeat_with(|| rand::random::<u64>() % 1000)
+        .take(10_000_000)
+        .collect();
+    let mut group = c.benchmark_group("Sort");
+    group.warm_up_time(std::time::Duration::new(1, 0));
+    group.measurement_time(std::time::Duration::new(3, 0));
+    group.sample_size(10);
+    group.nresamples(10);
+
+    let cpus: Vec<usize> = vec![1, 2, 3, 4, 8, 16, 24, 32]
+        .iter()
+        .filter(|&&i| i <= num_cpus::get())
+        .cloned()
+        .collect();
+
+    let mut test: Vec<TestConfig<u64>> = vec![];
+    for i in &cpus {
+        let t = TestConfig::new(data.len(), *i, Some(0), Sort::new(&data));
+        test.push(t);
+        let t = TestConfig::new(data.len(), *i, None, RayonSort::new(&data));
+        test.push(t);
+    }
+    let mut r = RayonSort::new(&data);
+    let mut t = Tester::new(test, group, r.start());
+    t.run();
+}
+criterion_group!(benches, bench);
+criterion_main!(benches);
@@ -3,12 +3,17 @@ use adaptive_algorithms::rayon::*;
 
 fn main() {
     let points = Point::create_random_points(10000);
-    let pool = get_custom_thread_pool(4,8);
+    let builder = AdaptivePoolBuilder::new().num_threads(4).strategy(8);
+    #[cfg(feature = "logs")]
+    let builder = builder.logs();
+    let pool = builder.build();
     // let pool = get_thread_pool();
 
 
     for _ in 0..100 {
     let mut s = Searcher::new(&points);
+        #[cfg(feature = "statistics")]
+        adaptive_algorithms::statistics::reset(&pool);
         #[cfg(feature = "logs")]
         {
             let (_, log) = pool.logging_install(|| s.run());
@@ -20,6 +25,6 @@ fn main() {
             pool.install(|| s.run());
         }
         #[cfg(feature = "statistics")]
-        adaptive_algorithms::task::print_statistics();
+        println!("{}", adaptive_algorithms::statistics::of(&pool));
     }
 }
@@ -5,10 +5,14 @@ use adaptive_algorithms::rayon::*;
 use adaptive_algorithms::adaptive_bench::*;
 use rayon_logs::prelude::*;
 use rayon_logs::Logged;
-use rayon::prelude::*;
+use adaptive_algorithms::rayon_crate::prelude::*;
 fn main() {
     let points = Point::create_random_points(50000);
-    let pool = rayon_logs::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+    let pool = AdaptivePoolBuilder::new()
+        .num_threads(4)
+        .without_steal_requests()
+        .logs()
+        .build();
 
     // let mut s = RayonPoints::new(&points);
     
@@ -34,5 +38,5 @@ fn main() {
     //     pool.install(|| s.run());
     // }
     // #[cfg(feature = "statistics")]
-    // adaptive_algorithms::task::print_statistics();
+    // println!("{}", adaptive_algorithms::statistics::of(&pool));
 }
@@ -11,9 +11,17 @@ fn main() {
 
     let procs: Vec<u64> = std::iter::repeat(0).take(3).collect();
 
-    let pool = get_custom_thread_pool(num_cpus::get(), 20);
+    let builder = AdaptivePoolBuilder::new()
+        .num_threads(num_cpus::get())
+        .strategy(20);
+    // rayon_logs names its threads itself
+    #[cfg(feature = "logs")]
+    let builder = builder.logs();
+    #[cfg(not(feature = "logs"))]
+    let builder = builder.thread_name(|i| format!("scheduling-{}", i));
+    let pool = builder.build();
 
-    let mut s = Scheduling::new(&times, &procs);
+    let mut s = Scheduling::new(&times, &procs, 8);
 
     #[cfg(feature = "logs")]
     {
@@ -28,5 +36,8 @@ fn main() {
     }
 
     #[cfg(feature = "statistics")]
-    adaptive_algorithms::task::print_statistics();
+    {
+        println!("{}", adaptive_algorithms::statistics::of(&pool));
+        println!("Pruned schedules: {}", s.pruned);
+    }
 }
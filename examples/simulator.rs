@@ -0,0 +1,20 @@
+use adaptive_algorithms::points::*;
+use adaptive_algorithms::policy::*;
+use adaptive_algorithms::simulator::Simulator;
+use std::sync::Arc;
+
+// Compare split policies and how long thieves wait, without any noise from the machine
+fn main() {
+    let points = Point::create_random_points(2000);
+    let policies: Vec<Arc<dyn SplitPolicy>> =
+        vec![Arc::new(Half), Arc::new(Equal), Arc::new(Geometric)];
+    for policy in policies {
+        for wait in vec![10, 100, 1000] {
+            let simulator = Simulator::new(8).steal_latency(20).steal_wait(wait).seed(1);
+            let mut s = Searcher::new(&points).with_policy(policy.clone());
+            // every step is one point, thieves waiting less than the latency never get anything
+            let report = simulator.run(&mut s, |_| 1000);
+            println!("{:?}, wait {}: {}", policy, wait, report);
+        }
+    }
+}
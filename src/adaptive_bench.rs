@@ -1,19 +1,19 @@
+use crate::polling::Detection;
+use crate::rayon::{AdaptivePool, AdaptivePoolBuilder};
 pub use crate::steal;
+use crate::steal::StealStrategy;
 
 pub trait Benchable<'a, R>: Send + Sync {
     fn start(&mut self) -> Option<R>; // run the test
     fn name(&self) -> &'static str; // give it a nice name
                                     // fn id(&self) -> BenchmarkId; // not required, we create one directly
-    fn get_thread_pool(&self, num_threads: usize, backoffs: Option<usize>) -> rayon::ThreadPool {
-        let mut pool = rayon::ThreadPoolBuilder::new().num_threads(num_threads);
-        if let Some(backoffs) = backoffs {
-            // if backoffs == 0 {
-                pool = pool.steal_callback(move |x| steal::optimized_steal(x))
-            // } else {
-                // pool = pool.steal_callback(move |x| steal::steal(backoffs, x));
-            // }
-        }
-        pool.build().unwrap()
+    // Gets the builder set up for the test config, tests can change it before building
+    fn get_thread_pool(&self, builder: AdaptivePoolBuilder) -> AdaptivePool {
+        builder.build()
+    }
+    // Anything else worth knowing about the last run, printed once with the statistics
+    fn summary(&self) -> Option<String> {
+        None
     }
     // reset the test, will get called after every test so we can reuse it.
 }
@@ -41,11 +41,9 @@ where
         }
     }
     pub fn run(&mut self) {
+        use crate::statistics;
         for test in &mut self.tests {
-            #[cfg(feature = "statistics")] {
-            use crate::task::{reset_statistics};
-            reset_statistics();
-            }
+            let collect_statistics = test.statistics.unwrap_or_else(statistics::enabled);
             let group = &mut self.group;
             // let checksum = self.checksum;
             group.bench_with_input(
@@ -53,6 +51,7 @@ where
                 &self.result,
                 |b, result| {
                     let pool = test.get_thread_pool();
+                    statistics::reset(&pool);
                     b.iter_batched(
                         || (),
                         |_| {
@@ -66,12 +65,14 @@ where
                         },
                         BatchSize::SmallInput,
                     );
+                    if collect_statistics {
+                        println!("{}", statistics::of(&pool));
+                        if let Some(summary) = test.test.summary() {
+                            println!("{}", summary);
+                        }
+                    }
                 },
             );
-           #[cfg(feature = "statistics")] {
-            use crate::task::{print_statistics};
-            print_statistics();
-            }
         }
     }
 }
@@ -82,7 +83,13 @@ where
 {
     pub len: usize,
     pub num_cpus: usize,
-    pub backoff: Option<usize>,
+    pub backoff: Option<StealStrategy>,
+    // None is whatever `task::multisplit` says
+    pub multisplit: Option<bool>,
+    // collect statistics just for this test
+    pub statistics: Option<bool>,
+    // None is steal requests
+    pub detection: Option<Detection>,
     pub test: Box<dyn Benchable<'a, R> + 'a>,
 }
 impl<'a, R> TestConfig<'a, R>
@@ -98,24 +105,70 @@ where
         TestConfig {
             len,
             num_cpus,
-            backoff,
+            // the benches always meant optimized_steal by 0
+            backoff: backoff.map(|backoff| match backoff {
+                0 => StealStrategy::Optimized,
+                backoffs => StealStrategy::from(backoffs),
+            }),
+            multisplit: None,
+            statistics: None,
+            detection: None,
             test: Box::new(test),
         }
     }
-    pub fn get_thread_pool(&self) -> rayon::ThreadPool {
-        self.test.get_thread_pool(self.num_cpus, self.backoff)
+    pub fn with_strategy(mut self, strategy: StealStrategy) -> Self {
+        self.backoff = Some(strategy);
+        self
+    }
+    pub fn with_multisplit(mut self, multisplit: bool) -> Self {
+        self.multisplit = Some(multisplit);
+        self
+    }
+    pub fn with_statistics(mut self, statistics: bool) -> Self {
+        self.statistics = Some(statistics);
+        self
+    }
+    /// Polling runs on a stock rayon pool, the steal strategy doesn't matter then
+    pub fn with_detection(mut self, detection: Detection) -> Self {
+        self.detection = Some(detection);
+        self
+    }
+    // Without a strategy nobody sends steal requests
+    pub fn get_thread_pool(&self) -> AdaptivePool {
+        let mut builder = AdaptivePoolBuilder::new()
+            .num_threads(self.num_cpus)
+            .detection(self.detection.unwrap_or(Detection::StealRequests));
+        builder = match self.backoff {
+            Some(strategy) => builder.strategy(strategy),
+            None => builder.without_steal_requests(),
+        };
+        if let Some(statistics) = self.statistics {
+            builder = builder.statistics(statistics);
+        }
+        if let Some(multisplit) = self.multisplit {
+            builder = builder.multisplit(multisplit);
+        }
+        self.test.get_thread_pool(builder)
     }
     pub fn name(&self) -> String {
-        let backoff = if let Some(backoff) = self.backoff {
-            if backoff == 0 {
-                "".to_string()
-            // "optimized".to_string() + "/"
-            } else {
-                "/".to_string() + &backoff.to_string()
-            }
+        let backoff = match self.backoff {
+            Some(StealStrategy::Optimized) | None => "".to_string(),
+            Some(strategy) => "/".to_string() + &strategy.to_string(),
+        };
+        let multisplit = match self.multisplit {
+            Some(true) => "/multisplit",
+            Some(false) => "/binary",
+            None => "",
+        };
+        let statistics = if self.statistics == Some(true) {
+            "/statistics"
         } else {
-            "".to_string()
+            ""
+        };
+        let detection = match self.detection {
+            Some(detection) => "/".to_string() + &detection.to_string(),
+            None => "".to_string(),
         };
-        self.test.name().to_string() + &backoff //+ "_" + &self.len.to_string()
+        self.test.name().to_string() + &backoff + &detection + multisplit + statistics //+ "_" + &self.len.to_string()
     }
 }
@@ -0,0 +1,573 @@
+//! Adaptive iterators, in the style of rayon's `ParallelIterator`. The adaptors only describe
+//! what happens to every element; the work itself is done by a single `Task` walking over a
+//! range of base indices, so we only split when `steal::get_my_steal_count` tells us to.
+use crate::task::{CancellationToken, Task};
+use std::collections::LinkedList;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// how many base elements we process before checking for steal requests again
+const BLOCK_SIZE: usize = 1024;
+
+pub trait AdaptiveIterator: Sized + Sync {
+    type Item;
+    /// Number of base elements (before filtering).
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Fold the base elements in `range`, in order, into `init`.
+    fn fold_range<B, F>(&self, range: Range<usize>, init: B, f: F) -> B
+    where
+        F: FnMut(B, Self::Item) -> B;
+    /// How many base elements we do in one step. Folding calibrates this to the target step
+    /// time (see `task::set_target_step_time`), so it's only where we start from.
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn map<F, R>(self, op: F) -> Map<Self, F>
+    where
+        F: Fn(Self::Item) -> R + Sync,
+    {
+        Map { base: self, op }
+    }
+    fn filter<P>(self, predicate: P) -> Filter<Self, P>
+    where
+        P: Fn(&Self::Item) -> bool + Sync,
+    {
+        Filter {
+            base: self,
+            predicate,
+        }
+    }
+    fn filter_map<F, R>(self, op: F) -> FilterMap<Self, F>
+    where
+        F: Fn(Self::Item) -> Option<R> + Sync,
+    {
+        FilterMap { base: self, op }
+    }
+    fn enumerate(self) -> Enumerate<Self>
+    where
+        Self: ExactAdaptiveIterator,
+    {
+        Enumerate { base: self }
+    }
+    /// Start with bigger or smaller steps, e.g. 1 if every element is a lot of work.
+    fn with_block_size(self, block_size: usize) -> WithBlockSize<Self> {
+        assert!(block_size > 0);
+        WithBlockSize {
+            base: self,
+            block_size,
+        }
+    }
+
+    /// Every piece starts from `identity()` and folds its elements with `fold_op`, neighbouring
+    /// pieces are merged (left to right) with `combine`.
+    fn fold<B, ID, F, C>(self, identity: ID, fold_op: F, combine: C) -> B
+    where
+        B: Send,
+        ID: Fn() -> B + Sync,
+        F: Fn(B, Self::Item) -> B + Sync,
+        C: Fn(B, B) -> B + Sync,
+    {
+        let mut folder = Folder {
+            iter: &self,
+            start_index: 0,
+            end_index: self.len(),
+            identity: &identity,
+            fold: &fold_op,
+            combine: &combine,
+            result: Some(identity()),
+            block_size: self.block_size(),
+        };
+        folder.run();
+        folder.result.unwrap()
+    }
+    fn reduce<ID, C>(self, identity: ID, op: C) -> Self::Item
+    where
+        Self::Item: Send,
+        ID: Fn() -> Self::Item + Sync,
+        C: Fn(Self::Item, Self::Item) -> Self::Item + Sync,
+    {
+        self.fold(&identity, &op, &op)
+    }
+    fn for_each<F>(self, op: F)
+    where
+        F: Fn(Self::Item) + Sync,
+    {
+        self.fold(|| (), |_, x| op(x), |_, _| ())
+    }
+    fn count(self) -> usize {
+        self.fold(|| 0, |c, _| c + 1, |a, b| a + b)
+    }
+    /// Some item matching `predicate`, all pieces stop as soon as one of them found one.
+    fn find_any<P>(self, predicate: P) -> Option<Self::Item>
+    where
+        Self::Item: Send,
+        P: Fn(&Self::Item) -> bool + Sync,
+    {
+        find(&self, &predicate, Some(CancellationToken::new()))
+    }
+    /// The first item matching `predicate`. Only the pieces right of a match can stop early.
+    fn find_first<P>(self, predicate: P) -> Option<Self::Item>
+    where
+        Self::Item: Send,
+        P: Fn(&Self::Item) -> bool + Sync,
+    {
+        find(&self, &predicate, None)
+    }
+    fn any<P>(self, predicate: P) -> bool
+    where
+        P: Fn(Self::Item) -> bool + Sync,
+    {
+        self.map(predicate).find_any(|x| *x).is_some()
+    }
+    fn all<P>(self, predicate: P) -> bool
+    where
+        P: Fn(Self::Item) -> bool + Sync,
+    {
+        !self.any(move |x| !predicate(x))
+    }
+    /// Collect all items into one chunk per piece. Pieces are linked when fusing, so
+    /// nothing gets copied until we flatten the list.
+    fn collect_chunks(self) -> LinkedList<Vec<Self::Item>>
+    where
+        Self::Item: Send,
+    {
+        self.fold(
+            LinkedList::new,
+            |mut list, x| {
+                if list.is_empty() {
+                    list.push_back(Vec::new());
+                }
+                list.back_mut().unwrap().push(x);
+                list
+            },
+            |mut a, mut b| {
+                a.append(&mut b);
+                a
+            },
+        )
+    }
+    /// Collect all items, in the same order as the base elements.
+    fn collect(self) -> Vec<Self::Item>
+    where
+        Self::Item: Send,
+    {
+        let chunks = self.collect_chunks();
+        let mut v = Vec::with_capacity(chunks.iter().map(|c| c.len()).sum());
+        for mut chunk in chunks {
+            v.append(&mut chunk);
+        }
+        v
+    }
+}
+
+/// Iterators where every base element gives exactly one item, so positions are known.
+pub trait ExactAdaptiveIterator: AdaptiveIterator {}
+
+pub trait AdaptiveSlice<T: Sync> {
+    fn adaptive_iter(&self) -> Iter<'_, T>;
+}
+impl<T: Sync> AdaptiveSlice<T> for [T] {
+    fn adaptive_iter(&self) -> Iter<'_, T> {
+        Iter { slice: self }
+    }
+}
+
+pub struct Iter<'a, T> {
+    slice: &'a [T],
+}
+impl<'a, T: Sync> AdaptiveIterator for Iter<'a, T> {
+    type Item = &'a T;
+    fn len(&self) -> usize {
+        self.slice.len()
+    }
+    fn fold_range<B, F>(&self, range: Range<usize>, init: B, f: F) -> B
+    where
+        F: FnMut(B, Self::Item) -> B,
+    {
+        self.slice[range].iter().fold(init, f)
+    }
+}
+impl<'a, T: Sync> ExactAdaptiveIterator for Iter<'a, T> {}
+
+pub struct Map<I, F> {
+    base: I,
+    op: F,
+}
+impl<I, F, R> AdaptiveIterator for Map<I, F>
+where
+    I: AdaptiveIterator,
+    F: Fn(I::Item) -> R + Sync,
+{
+    type Item = R;
+    fn len(&self) -> usize {
+        self.base.len()
+    }
+    fn fold_range<B, G>(&self, range: Range<usize>, init: B, mut f: G) -> B
+    where
+        G: FnMut(B, Self::Item) -> B,
+    {
+        let op = &self.op;
+        self.base.fold_range(range, init, |acc, x| f(acc, op(x)))
+    }
+    fn block_size(&self) -> usize {
+        self.base.block_size()
+    }
+}
+impl<I, F, R> ExactAdaptiveIterator for Map<I, F>
+where
+    I: ExactAdaptiveIterator,
+    F: Fn(I::Item) -> R + Sync,
+{
+}
+
+pub struct Filter<I, P> {
+    base: I,
+    predicate: P,
+}
+impl<I, P> AdaptiveIterator for Filter<I, P>
+where
+    I: AdaptiveIterator,
+    P: Fn(&I::Item) -> bool + Sync,
+{
+    type Item = I::Item;
+    fn len(&self) -> usize {
+        self.base.len()
+    }
+    fn fold_range<B, F>(&self, range: Range<usize>, init: B, mut f: F) -> B
+    where
+        F: FnMut(B, Self::Item) -> B,
+    {
+        let predicate = &self.predicate;
+        self.base.fold_range(range, init, |acc, x| {
+            if predicate(&x) {
+                f(acc, x)
+            } else {
+                acc
+            }
+        })
+    }
+    fn block_size(&self) -> usize {
+        self.base.block_size()
+    }
+}
+
+pub struct FilterMap<I, F> {
+    base: I,
+    op: F,
+}
+impl<I, F, R> AdaptiveIterator for FilterMap<I, F>
+where
+    I: AdaptiveIterator,
+    F: Fn(I::Item) -> Option<R> + Sync,
+{
+    type Item = R;
+    fn len(&self) -> usize {
+        self.base.len()
+    }
+    fn fold_range<B, G>(&self, range: Range<usize>, init: B, mut f: G) -> B
+    where
+        G: FnMut(B, Self::Item) -> B,
+    {
+        let op = &self.op;
+        self.base.fold_range(range, init, |acc, x| match op(x) {
+            Some(x) => f(acc, x),
+            None => acc,
+        })
+    }
+    fn block_size(&self) -> usize {
+        self.base.block_size()
+    }
+}
+
+pub struct Enumerate<I> {
+    base: I,
+}
+impl<I: ExactAdaptiveIterator> AdaptiveIterator for Enumerate<I> {
+    type Item = (usize, I::Item);
+    fn len(&self) -> usize {
+        self.base.len()
+    }
+    fn fold_range<B, F>(&self, range: Range<usize>, init: B, mut f: F) -> B
+    where
+        F: FnMut(B, Self::Item) -> B,
+    {
+        let mut index = range.start;
+        self.base.fold_range(range, init, |acc, x| {
+            let acc = f(acc, (index, x));
+            index += 1;
+            acc
+        })
+    }
+    fn block_size(&self) -> usize {
+        self.base.block_size()
+    }
+}
+impl<I: ExactAdaptiveIterator> ExactAdaptiveIterator for Enumerate<I> {}
+
+pub struct WithBlockSize<I> {
+    base: I,
+    block_size: usize,
+}
+impl<I: AdaptiveIterator> AdaptiveIterator for WithBlockSize<I> {
+    type Item = I::Item;
+    fn len(&self) -> usize {
+        self.base.len()
+    }
+    fn fold_range<B, F>(&self, range: Range<usize>, init: B, f: F) -> B
+    where
+        F: FnMut(B, Self::Item) -> B,
+    {
+        self.base.fold_range(range, init, f)
+    }
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+}
+impl<I: ExactAdaptiveIterator> ExactAdaptiveIterator for WithBlockSize<I> {}
+
+// The one task doing the work for all the consumers above
+struct Folder<'a, I, B, ID, F, C> {
+    iter: &'a I,
+    start_index: usize,
+    end_index: usize,
+    identity: &'a ID,
+    fold: &'a F,
+    combine: &'a C,
+    // only an option so we can move the value out while folding
+    result: Option<B>,
+    // starts at the block size of the iterator, then gets calibrated
+    block_size: usize,
+}
+
+impl<'a, I, B, ID, F, C> Task for Folder<'a, I, B, ID, F, C>
+where
+    I: AdaptiveIterator,
+    B: Send,
+    ID: Fn() -> B + Sync,
+    F: Fn(B, I::Item) -> B + Sync,
+    C: Fn(B, B) -> B + Sync,
+{
+    fn step(&mut self) {
+        let end_index = self.start_index.saturating_add(self.block_size).min(self.end_index);
+        let result = self.iter.fold_range(
+            self.start_index..end_index,
+            self.result.take().unwrap(),
+            self.fold,
+        );
+        self.result = Some(result);
+        self.start_index = end_index;
+    }
+    fn can_split(&self) -> bool {
+        self.end_index - self.start_index > self.block_size
+    }
+    fn step_size(&self) -> Option<usize> {
+        Some(self.block_size)
+    }
+    fn set_step_size(&mut self, size: usize) {
+        self.block_size = size;
+    }
+    fn split(&mut self, mut runner: impl FnMut(&mut Vec<&mut Self>), steal_counter: usize) {
+        let mut start_index = self.start_index;
+        let end_index = self.end_index;
+        // how many elements per task? We need at least one
+        let step = (end_index - start_index) / (steal_counter + 1) + 1;
+        let mut tasks = vec![];
+        self.end_index = start_index + step;
+        start_index += step;
+        while start_index < end_index {
+            let other = Folder {
+                iter: self.iter,
+                start_index,
+                end_index: (start_index + step).min(end_index),
+                identity: self.identity,
+                fold: self.fold,
+                combine: self.combine,
+                result: Some((self.identity)()),
+                block_size: self.block_size,
+            };
+            tasks.push(other);
+            start_index += step;
+        }
+        let mut tasks = tasks.iter_mut().collect::<Vec<&mut Self>>();
+        tasks.insert(0, self);
+        runner(&mut tasks);
+    }
+    fn is_finished(&self) -> bool {
+        self.end_index == self.start_index
+    }
+    fn fuse(&mut self, other: &mut Self) {
+        // other is always the part right of us, so non-commutative combines work too
+        let left = self.result.take().unwrap();
+        let right = other.result.take().unwrap();
+        self.result = Some((self.combine)(left, right));
+    }
+}
+
+fn find<I, P>(iter: &I, predicate: &P, cancel: Option<CancellationToken>) -> Option<I::Item>
+where
+    I: AdaptiveIterator,
+    I::Item: Send,
+    P: Fn(&I::Item) -> bool + Sync,
+{
+    let bound = AtomicUsize::new(std::usize::MAX);
+    let mut finder = Finder {
+        iter,
+        start_index: 0,
+        end_index: iter.len(),
+        predicate,
+        bound: &bound,
+        cancel,
+        found: None,
+    };
+    finder.run();
+    finder.found
+}
+
+// Task behind find_any and find_first
+struct Finder<'a, I: AdaptiveIterator, P> {
+    iter: &'a I,
+    start_index: usize,
+    end_index: usize,
+    predicate: &'a P,
+    // start of the leftmost block with a match, nothing right of it matters anymore
+    bound: &'a AtomicUsize,
+    // only set if any match will do, then we stop everyone at the first one
+    cancel: Option<CancellationToken>,
+    found: Option<I::Item>,
+}
+
+impl<'a, I, P> Task for Finder<'a, I, P>
+where
+    I: AdaptiveIterator,
+    I::Item: Send,
+    P: Fn(&I::Item) -> bool + Sync,
+{
+    fn step(&mut self) {
+        let end_index = (self.start_index + self.iter.block_size()).min(self.end_index);
+        let predicate = self.predicate;
+        let found = self
+            .iter
+            .fold_range(self.start_index..end_index, None, |found, x| {
+                if found.is_none() && predicate(&x) {
+                    Some(x)
+                } else {
+                    found
+                }
+            });
+        if found.is_some() {
+            self.found = found;
+            self.bound.fetch_min(self.start_index, Ordering::Relaxed);
+            if let Some(cancel) = &self.cancel {
+                cancel.cancel();
+            }
+            self.start_index = self.end_index;
+        } else {
+            self.start_index = end_index;
+        }
+    }
+    fn can_split(&self) -> bool {
+        self.end_index - self.start_index > self.iter.block_size()
+    }
+    fn split(&mut self, mut runner: impl FnMut(&mut Vec<&mut Self>), steal_counter: usize) {
+        let mut start_index = self.start_index;
+        let end_index = self.end_index;
+        // how many elements per task? We need at least one
+        let step = (end_index - start_index) / (steal_counter + 1) + 1;
+        let mut tasks = vec![];
+        self.end_index = start_index + step;
+        start_index += step;
+        while start_index < end_index {
+            let other = Finder {
+                iter: self.iter,
+                start_index,
+                end_index: (start_index + step).min(end_index),
+                predicate: self.predicate,
+                bound: self.bound,
+                cancel: self.cancel.clone(),
+                found: None,
+            };
+            tasks.push(other);
+            start_index += step;
+        }
+        let mut tasks = tasks.iter_mut().collect::<Vec<&mut Self>>();
+        tasks.insert(0, self);
+        runner(&mut tasks);
+    }
+    fn is_finished(&self) -> bool {
+        self.end_index == self.start_index
+            || self.start_index >= self.bound.load(Ordering::Relaxed)
+    }
+    fn cancellation(&self) -> Option<&CancellationToken> {
+        self.cancel.as_ref()
+    }
+    fn fuse(&mut self, other: &mut Self) {
+        // we're left of other, so our match comes first
+        if self.found.is_none() {
+            self.found = other.found.take();
+        }
+    }
+}
+
+#[test]
+fn test_iter() {
+    use crate::rayon::get_thread_pool;
+    let v: Vec<u64> = std::iter::repeat_with(|| rand::random::<u64>() % 10_000)
+        .take(100_000)
+        .collect();
+    let pool = get_thread_pool();
+    let sum = pool.install(|| {
+        v.adaptive_iter()
+            .map(|x| x * 2)
+            .filter(|x| x % 3 == 0)
+            .reduce(|| 0, |a, b| a + b)
+    });
+    let expected: u64 = v.iter().map(|x| x * 2).filter(|x| x % 3 == 0).sum();
+    assert_eq!(sum, expected);
+    let positions = pool.install(|| {
+        v.adaptive_iter()
+            .enumerate()
+            .filter(|(_, x)| **x < 100)
+            .map(|(i, _)| i)
+            .collect()
+    });
+    let expected: Vec<usize> = (0..v.len()).filter(|&i| v[i] < 100).collect();
+    assert_eq!(positions, expected);
+}
+
+#[test]
+fn test_collect() {
+    use crate::rayon::get_thread_pool;
+    let v: Vec<u64> = (0..1_000_000).collect();
+    let pool = get_thread_pool();
+    let odd = pool.install(|| {
+        v.adaptive_iter()
+            .filter_map(|x| if x % 2 == 1 { Some(x * 10) } else { None })
+            .collect()
+    });
+    assert_eq!(odd.len(), v.len() / 2);
+    // we only keep the order if every piece got fused with its right neighbour
+    assert!(odd.windows(2).all(|w| w[0] < w[1]));
+    let copy = pool.install(|| v.adaptive_iter().map(|x| *x).collect());
+    assert_eq!(copy, v);
+}
+
+#[test]
+fn test_find() {
+    use crate::rayon::get_thread_pool;
+    let v: Vec<u64> = std::iter::repeat_with(|| rand::random::<u64>() % 1_000_000)
+        .take(1_000_000)
+        .collect();
+    let pool = get_thread_pool();
+    let first = pool.install(|| v.adaptive_iter().enumerate().find_first(|(_, x)| **x < 10));
+    let expected = v.iter().enumerate().find(|(_, x)| **x < 10);
+    assert_eq!(first, expected);
+    let any = pool.install(|| v.adaptive_iter().find_any(|x| **x < 10));
+    assert_eq!(any.is_some(), expected.is_some());
+    assert!(any.map_or(true, |x| *x < 10));
+    assert!(pool.install(|| v.adaptive_iter().all(|x| *x < 1_000_000)));
+    assert!(!pool.install(|| v.adaptive_iter().any(|x| *x >= 1_000_000)));
+}
@@ -2,17 +2,43 @@ extern crate rand;
 
 // use rayon::prelude::*;
 pub mod adaptive_bench;
+pub mod iter;
+pub mod merge;
 pub mod points;
+pub mod policy;
+pub mod polling;
+pub mod pool;
 pub mod rayon;
+pub mod reduce;
+pub mod scan;
 pub mod scheduling;
+pub mod simulator;
+pub mod sort;
+pub mod statistics;
 pub mod steal;
 pub mod task;
+pub mod topology;
 #[macro_use]
 extern crate lazy_static;
 
+/// The rayon we run on: the fork, which sends steal requests through its steal callback, or
+/// stock rayon (feature `stock-rayon`). Use this one, so parallel iterators run in our pools.
+#[cfg(feature = "steal-callback")]
+pub use rayon_fork as rayon_crate;
+#[cfg(not(feature = "steal-callback"))]
+pub use ::rayon as rayon_crate;
+#[cfg(not(any(feature = "steal-callback", feature = "stock-rayon")))]
+compile_error!("enable either the `steal-callback` or the `stock-rayon` feature");
+
 pub use crate::adaptive_bench::Benchable;
+pub use crate::iter::{AdaptiveIterator, AdaptiveSlice};
+pub use crate::merge::adaptive_merge;
+pub use crate::policy::SplitPolicy;
+pub use crate::reduce::{adaptive_for_each, adaptive_reduce};
+pub use crate::scan::{adaptive_prefix_sum, adaptive_scan};
 pub use crate::scheduling::*;
-pub use crate::task::Task;
+pub use crate::sort::adaptive_sort;
+pub use crate::task::{Enclosing, Nest, OutputTask, Task, TryTask};
 pub fn main() {
     // let remaining_times = vec![
     //     1, 2, 5, 20, 09, 20, 42, 13, 4, 20, 64, 6, 84, 20, 01, 91, 100, 5, 42, 25, 65, 39, 62, 35, 60, 25, 29, 53
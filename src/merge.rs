@@ -0,0 +1,135 @@
+//! Adaptive merge of two sorted slices into an output slice. When splitting we cut the output
+//! into equal parts and find with a binary search (co-ranking) how many elements of each
+//! input end up in each part. Pieces write directly into their part of the output, so there
+//! is nothing to do when fusing.
+use crate::task::Task;
+
+// how many elements we output before checking for steal requests again
+const BLOCK_SIZE: usize = 1024;
+
+struct Merger<'a, T> {
+    a: &'a [T],
+    b: &'a [T],
+    out: &'a mut [T],
+}
+
+// How many elements of a and b end up in the first k elements of the output.
+fn co_rank<T: Ord>(k: usize, a: &[T], b: &[T]) -> (usize, usize) {
+    let mut low = k.saturating_sub(b.len());
+    let mut high = k.min(a.len());
+    while low < high {
+        let i = (low + high) / 2;
+        // a wins ties, so a[i] is in the first k if it isn't bigger than b[k - i - 1]
+        if a[i] <= b[k - i - 1] {
+            low = i + 1;
+        } else {
+            high = i;
+        }
+    }
+    (low, k - low)
+}
+
+impl<'a, T> Task for Merger<'a, T>
+where
+    T: Ord + Clone + Send + Sync,
+{
+    fn step(&mut self) {
+        let size = BLOCK_SIZE.min(self.out.len());
+        let (block, rest) = std::mem::take(&mut self.out).split_at_mut(size);
+        let (a, b) = (self.a, self.b);
+        let (mut i, mut j) = (0, 0);
+        for x in block.iter_mut() {
+            // take from a on equal elements to keep the merge stable
+            if j == b.len() || (i < a.len() && a[i] <= b[j]) {
+                *x = a[i].clone();
+                i += 1;
+            } else {
+                *x = b[j].clone();
+                j += 1;
+            }
+        }
+        self.a = &a[i..];
+        self.b = &b[j..];
+        self.out = rest;
+    }
+    fn can_split(&self) -> bool {
+        self.out.len() > BLOCK_SIZE
+    }
+    fn split(&mut self, mut runner: impl FnMut(&mut Vec<&mut Self>), steal_counter: usize) {
+        let len = self.out.len();
+        // how many elements per task? We need at least one
+        let step = len / (steal_counter + 1) + 1;
+        let (a, b) = (self.a, self.b);
+        // where each piece ends in a and b
+        let mut ends = vec![];
+        let mut k = step;
+        while k < len {
+            ends.push(co_rank(k, a, b));
+            k += step;
+        }
+        ends.push((a.len(), b.len()));
+        let (i, j) = ends[0];
+        let (mine, mut rest) = std::mem::take(&mut self.out).split_at_mut(i + j);
+        self.a = &a[..i];
+        self.b = &b[..j];
+        self.out = mine;
+        let mut tasks = vec![];
+        let (mut start_a, mut start_b) = (i, j);
+        for &(end_a, end_b) in &ends[1..] {
+            let (piece, remaining) = rest.split_at_mut(end_a - start_a + end_b - start_b);
+            tasks.push(Merger {
+                a: &a[start_a..end_a],
+                b: &b[start_b..end_b],
+                out: piece,
+            });
+            rest = remaining;
+            start_a = end_a;
+            start_b = end_b;
+        }
+        let mut tasks = tasks.iter_mut().collect::<Vec<&mut Self>>();
+        tasks.insert(0, self);
+        runner(&mut tasks);
+    }
+    fn is_finished(&self) -> bool {
+        self.out.is_empty()
+    }
+    fn fuse(&mut self, _other: &mut Self) {
+        // Nothing to do, the output is written in place
+    }
+}
+
+/// Merge the sorted slices `a` and `b` into `out`, which needs to have exactly the size of
+/// both. The merge is stable, on equal elements the ones from `a` come first.
+pub fn adaptive_merge<T>(a: &[T], b: &[T], out: &mut [T])
+where
+    T: Ord + Clone + Send + Sync,
+{
+    assert_eq!(a.len() + b.len(), out.len());
+    Merger { a, b, out }.run();
+}
+
+#[test]
+fn test_merge() {
+    use crate::rayon::get_thread_pool;
+    let random_sorted = |n| {
+        let mut v: Vec<u64> = std::iter::repeat_with(|| rand::random::<u64>() % 1000)
+            .take(n)
+            .collect();
+        v.sort();
+        v
+    };
+    let a = random_sorted(300_000);
+    let b = random_sorted(700_000);
+    let mut expected = [a.clone(), b.clone()].concat();
+    expected.sort();
+    let pool = get_thread_pool();
+    let mut out = vec![0; a.len() + b.len()];
+    pool.install(|| adaptive_merge(&a, &b, &mut out));
+    assert_eq!(out, expected);
+    for k in (0..out.len()).step_by(9999) {
+        let (i, j) = co_rank(k, &a, &b);
+        assert_eq!(i + j, k);
+        assert!(i == 0 || j == b.len() || a[i - 1] <= b[j]);
+        assert!(j == 0 || i == a.len() || b[j - 1] < a[i]);
+    }
+}
@@ -1,6 +1,10 @@
 use crate::adaptive_bench::Benchable;
+use crate::iter::{AdaptiveIterator, AdaptiveSlice};
+use crate::policy::{default_policy, SplitPolicy};
 pub use crate::task::Task;
+use crate::task::{Enclosing, Nest};
 use rand::Rng;
+use std::sync::Arc;
 
 #[derive(Debug, PartialEq)]
 pub struct Point {
@@ -30,6 +34,9 @@ pub struct Searcher<'a> {
     start_index: usize,
     end_index: usize,
     min: f64,
+    policy: Arc<dyn SplitPolicy>,
+    // how many points the inner loop looks at in a step, gets calibrated as we go
+    block_size: usize,
 }
 
 impl<'a> Searcher<'a> {
@@ -39,13 +46,23 @@ impl<'a> Searcher<'a> {
             start_index: 0,
             end_index: points.len(),
             min: 100.0,
+            policy: default_policy(),
+            block_size: 1024,
         }
     }
+    // How we split, this is used for the inner loop as well
+    pub fn with_policy(mut self, policy: Arc<dyn SplitPolicy>) -> Self {
+        self.policy = policy;
+        self
+    }
+    pub fn min(&self) -> f64 {
+        self.min
+    }
 }
 
 impl<'a> Benchable<'a, f64> for Searcher<'a> {
     fn start(&mut self) -> Option<f64> {
-        *self = Searcher::new(&self.points);
+        *self = Searcher::new(&self.points).with_policy(self.policy.clone());
         self.run();
         Some(self.min)
     }
@@ -56,43 +73,47 @@ impl<'a> Benchable<'a, f64> for Searcher<'a> {
 
 impl<'a> Task for Searcher<'a> {
     fn step(&mut self) {
+        self.step_nested(&mut ());
+    }
+    fn step_nested(&mut self, outer: &mut dyn Enclosing) {
+        // the inner loop can't borrow it from us, we get split while it runs
+        let policy = self.policy.clone();
+        // the point is the inner loop's now, a split only gives away the ones after it
+        let index = self.start_index;
+        self.start_index += 1;
         let mut t = Tester {
             points: self.points,
-            start_index: self.start_index + 1,
+            start_index: index + 1,
             end_index: self.points.len(),
             min: self.min,
-            point: &self.points[self.start_index],
+            point: &self.points[index],
+            policy: &*policy,
+            block_size: self.block_size,
         };
 
-        t.run_with(self);
+        t.run_nested(&mut Nest::new(self, outer));
         self.min = self.min.min(t.min);
-        self.start_index = (self.start_index + 1).min(self.end_index);
+        self.block_size = t.block_size;
     }
     fn can_split(&self) -> bool {
         return self.end_index - self.start_index > 1;
     }
     fn split(&mut self, mut runner: impl FnMut(&mut Vec<&mut Self>), steal_counter: usize) {
-        // let half = (self.end_index - self.start_index) / 2 + self.start_index;
-        let mut start_index = self.start_index;
-        let end_index = self.end_index;
-        // how many elements per task? We need at least one
-        let mut step = (end_index - start_index) / (steal_counter + 1) + 1;
-        if end_index - start_index <= 2 {
-            step = 1;
-        }
-        let mut tasks = vec![];
-        self.end_index = start_index + step;
-        start_index += step;
-        while start_index < end_index {
-            let other: Searcher<'a> = Searcher {
+        let ranges = self
+            .policy
+            .ranges(self.start_index..self.end_index, steal_counter);
+        self.end_index = ranges[0].end;
+        let mut tasks = ranges[1..]
+            .iter()
+            .map(|range| Searcher {
                 points: self.points,
-                start_index: start_index.min(end_index),
-                end_index: (start_index + step).min(end_index),
+                start_index: range.start,
+                end_index: range.end,
                 min: self.min,
-            };
-            tasks.push(other);
-            start_index += step;
-        }
+                policy: self.policy.clone(),
+                block_size: self.block_size,
+            })
+            .collect::<Vec<_>>();
         let mut tasks = tasks.iter_mut().collect::<Vec<&mut Self>>();
         tasks.insert(0, self);
        // println!("{:?}", tasks.iter().map(|x| (x.start_index, x.end_index)).collect::<Vec<_>>());
@@ -116,6 +137,8 @@ struct Tester<'a> {
     end_index: usize,
     min: f64,
     point: &'a Point,
+    policy: &'a dyn SplitPolicy,
+    block_size: usize,
 }
 
 impl<'a> Task for Tester<'a> {
@@ -123,7 +146,7 @@ impl<'a> Task for Tester<'a> {
     fn step(&mut self) {
         let mut min = self.min;
         let point = self.point;
-        let end_index = (self.start_index + 1024).min(self.end_index);
+        let end_index = self.start_index.saturating_add(self.block_size).min(self.end_index);
         let others = &self.points[self.start_index..end_index];
         for other in others {
             min = min.min(point.distance_to(other));
@@ -132,20 +155,35 @@ impl<'a> Task for Tester<'a> {
         self.start_index = end_index;
     }
     fn can_split(&self) -> bool {
-        self.end_index - self.start_index > 10000
-        // false
+        // not worth it if we're done in a few steps anyway
+        self.end_index - self.start_index > 10 * self.block_size
     }
-    fn split(&mut self, mut runner: impl FnMut(&mut Vec<&mut Self>), _steal_counter: usize) {
-        let half = (self.end_index - self.start_index) / 2 + self.start_index;
-        let mut other: Tester<'a> = Tester {
-            points: self.points,
-            point: self.point,
-            start_index: half,
-            end_index: self.end_index,
-            min: self.min,
-        };
-        self.end_index = half;
-        runner(&mut vec![self, &mut other]);
+    fn step_size(&self) -> Option<usize> {
+        Some(self.block_size)
+    }
+    fn set_step_size(&mut self, size: usize) {
+        self.block_size = size;
+    }
+    fn split(&mut self, mut runner: impl FnMut(&mut Vec<&mut Self>), steal_counter: usize) {
+        let ranges = self
+            .policy
+            .ranges(self.start_index..self.end_index, steal_counter);
+        self.end_index = ranges[0].end;
+        let mut tasks = ranges[1..]
+            .iter()
+            .map(|range| Tester {
+                points: self.points,
+                point: self.point,
+                start_index: range.start,
+                end_index: range.end,
+                min: self.min,
+                policy: self.policy,
+                block_size: self.block_size,
+            })
+            .collect::<Vec<_>>();
+        let mut tasks = tasks.iter_mut().collect::<Vec<&mut Self>>();
+        tasks.insert(0, self);
+        runner(&mut tasks);
     }
     fn is_finished(&self) -> bool {
         self.end_index == self.start_index
@@ -159,6 +197,236 @@ impl<'a> Task for Tester<'a> {
     // }
 }
 
+/// Smallest perimeter of a triangle between three of the points. That's a triple loop, so we
+/// nest three tasks and every level can get split.
+pub struct TriangleSearcher<'a> {
+    points: &'a [Point],
+    start_index: usize,
+    end_index: usize,
+    min: f64,
+    policy: Arc<dyn SplitPolicy>,
+    // how many points the innermost loop looks at in a step, gets calibrated as we go
+    block_size: usize,
+}
+
+impl<'a> TriangleSearcher<'a> {
+    pub fn new(points: &'a [Point]) -> Self {
+        TriangleSearcher {
+            points,
+            start_index: 0,
+            end_index: points.len(),
+            min: 100.0,
+            policy: default_policy(),
+            block_size: 1024,
+        }
+    }
+    pub fn with_policy(mut self, policy: Arc<dyn SplitPolicy>) -> Self {
+        self.policy = policy;
+        self
+    }
+    pub fn min(&self) -> f64 {
+        self.min
+    }
+}
+
+impl<'a> Task for TriangleSearcher<'a> {
+    fn step(&mut self) {
+        self.step_nested(&mut ());
+    }
+    fn step_nested(&mut self, outer: &mut dyn Enclosing) {
+        // the inner loop can't borrow it from us, we get split while it runs
+        let policy = self.policy.clone();
+        let first = self.start_index;
+        self.start_index += 1;
+        let mut t = EdgeSearcher {
+            points: self.points,
+            first,
+            start_index: first + 1,
+            end_index: self.points.len(),
+            min: self.min,
+            policy: &*policy,
+            block_size: self.block_size,
+        };
+        t.run_nested(&mut Nest::new(self, outer));
+        self.min = self.min.min(t.min);
+        self.block_size = t.block_size;
+    }
+    fn can_split(&self) -> bool {
+        self.end_index - self.start_index > 1
+    }
+    fn split(&mut self, mut runner: impl FnMut(&mut Vec<&mut Self>), steal_counter: usize) {
+        let ranges = self
+            .policy
+            .ranges(self.start_index..self.end_index, steal_counter);
+        self.end_index = ranges[0].end;
+        let mut tasks = ranges[1..]
+            .iter()
+            .map(|range| TriangleSearcher {
+                points: self.points,
+                start_index: range.start,
+                end_index: range.end,
+                min: self.min,
+                policy: self.policy.clone(),
+                block_size: self.block_size,
+            })
+            .collect::<Vec<_>>();
+        let mut tasks = tasks.iter_mut().collect::<Vec<&mut Self>>();
+        tasks.insert(0, self);
+        runner(&mut tasks);
+    }
+    fn is_finished(&self) -> bool {
+        self.end_index == self.start_index
+    }
+    fn fuse(&mut self, other: &mut Self) {
+        self.min = self.min.min(other.min);
+    }
+}
+
+// Second loop, the first point of the triangle is fixed
+struct EdgeSearcher<'a> {
+    points: &'a [Point],
+    first: usize,
+    start_index: usize,
+    end_index: usize,
+    min: f64,
+    policy: &'a dyn SplitPolicy,
+    block_size: usize,
+}
+
+impl<'a> Task for EdgeSearcher<'a> {
+    fn step(&mut self) {
+        self.step_nested(&mut ());
+    }
+    fn step_nested(&mut self, outer: &mut dyn Enclosing) {
+        let second = self.start_index;
+        self.start_index += 1;
+        let mut t = TriangleTester {
+            points: self.points,
+            a: &self.points[self.first],
+            b: &self.points[second],
+            start_index: second + 1,
+            end_index: self.points.len(),
+            min: self.min,
+            policy: self.policy,
+            block_size: self.block_size,
+        };
+        t.run_nested(&mut Nest::new(self, outer));
+        self.min = self.min.min(t.min);
+        self.block_size = t.block_size;
+    }
+    fn can_split(&self) -> bool {
+        self.end_index - self.start_index > 1
+    }
+    fn split(&mut self, mut runner: impl FnMut(&mut Vec<&mut Self>), steal_counter: usize) {
+        let ranges = self
+            .policy
+            .ranges(self.start_index..self.end_index, steal_counter);
+        self.end_index = ranges[0].end;
+        let mut tasks = ranges[1..]
+            .iter()
+            .map(|range| EdgeSearcher {
+                points: self.points,
+                first: self.first,
+                start_index: range.start,
+                end_index: range.end,
+                min: self.min,
+                policy: self.policy,
+                block_size: self.block_size,
+            })
+            .collect::<Vec<_>>();
+        let mut tasks = tasks.iter_mut().collect::<Vec<&mut Self>>();
+        tasks.insert(0, self);
+        runner(&mut tasks);
+    }
+    fn is_finished(&self) -> bool {
+        self.end_index == self.start_index
+    }
+    fn fuse(&mut self, other: &mut Self) {
+        self.min = self.min.min(other.min);
+    }
+}
+
+// Innermost loop, two points of the triangle are fixed
+struct TriangleTester<'a> {
+    points: &'a [Point],
+    a: &'a Point,
+    b: &'a Point,
+    start_index: usize,
+    end_index: usize,
+    min: f64,
+    policy: &'a dyn SplitPolicy,
+    block_size: usize,
+}
+
+impl<'a> Task for TriangleTester<'a> {
+    fn step(&mut self) {
+        let (a, b) = (self.a, self.b);
+        let ab = a.distance_to(b);
+        let end_index = self.start_index.saturating_add(self.block_size).min(self.end_index);
+        for c in &self.points[self.start_index..end_index] {
+            self.min = self.min.min(ab + b.distance_to(c) + a.distance_to(c));
+        }
+        self.start_index = end_index;
+    }
+    fn can_split(&self) -> bool {
+        // not worth it if we're done in a few steps anyway
+        self.end_index - self.start_index > 10 * self.block_size
+    }
+    fn step_size(&self) -> Option<usize> {
+        Some(self.block_size)
+    }
+    fn set_step_size(&mut self, size: usize) {
+        self.block_size = size;
+    }
+    fn split(&mut self, mut runner: impl FnMut(&mut Vec<&mut Self>), steal_counter: usize) {
+        let ranges = self
+            .policy
+            .ranges(self.start_index..self.end_index, steal_counter);
+        self.end_index = ranges[0].end;
+        let mut tasks = ranges[1..]
+            .iter()
+            .map(|range| TriangleTester {
+                points: self.points,
+                a: self.a,
+                b: self.b,
+                start_index: range.start,
+                end_index: range.end,
+                min: self.min,
+                policy: self.policy,
+                block_size: self.block_size,
+            })
+            .collect::<Vec<_>>();
+        let mut tasks = tasks.iter_mut().collect::<Vec<&mut Self>>();
+        tasks.insert(0, self);
+        runner(&mut tasks);
+    }
+    fn is_finished(&self) -> bool {
+        self.end_index == self.start_index
+    }
+    fn fuse(&mut self, other: &mut Self) {
+        self.min = self.min.min(other.min);
+    }
+}
+
+#[test]
+fn test_triangles() {
+    use crate::rayon::get_thread_pool;
+    let points = Point::create_random_points(200);
+    let mut expected = 100.0f64;
+    for i in 0..points.len() {
+        for j in i + 1..points.len() {
+            for k in j + 1..points.len() {
+                let (a, b, c) = (&points[i], &points[j], &points[k]);
+                expected = expected.min(a.distance_to(b) + b.distance_to(c) + a.distance_to(c));
+            }
+        }
+    }
+    let pool = get_thread_pool();
+    let mut s = TriangleSearcher::new(&points);
+    pool.install(|| s.run());
+    assert_eq!(s.min(), expected);
+}
+
 pub struct RayonPoints<'a> {
     points: &'a [Point],
 }
@@ -167,7 +435,7 @@ impl<'a> RayonPoints<'a> {
         RayonPoints { points }
     }
 }
-use rayon::prelude::*;
+use crate::rayon_crate::prelude::*;
 
 impl<'a> Benchable<'a, f64> for RayonPoints<'a> {
     fn start(&mut self) -> Option<f64> {
@@ -215,3 +483,31 @@ impl<'a> Benchable<'a, f64> for FlatMapPoints<'a> {
         "FlatMap"
     }
 }
+
+pub struct IterPoints<'a> {
+    points: &'a [Point],
+}
+impl<'a> IterPoints<'a> {
+    pub fn new(points: &'a [Point]) -> Self {
+        IterPoints { points }
+    }
+}
+impl<'a> Benchable<'a, f64> for IterPoints<'a> {
+    fn start(&mut self) -> Option<f64> {
+        let points = self.points;
+        let min = points
+            .adaptive_iter()
+            .enumerate()
+            // every point is a lot of work, we want to be able to split after each of them
+            .with_block_size(1)
+            .map(|(i, a)| {
+                let inner_iter = points[i + 1..].iter().map(|b| a.distance_to(b));
+                inner_iter.fold(1.0f64, |x, y| x.min(y))
+            })
+            .reduce(|| 1.0f64, |x, y| x.min(y));
+        Some(min)
+    }
+    fn name(&self) -> &'static str {
+        "AdaptiveIter"
+    }
+}
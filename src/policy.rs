@@ -0,0 +1,137 @@
+//! How a task gets split on a steal request: into how many pieces, and how big they are.
+//! Tasks keep an `Arc<dyn SplitPolicy>` and hand it down to their pieces, so one policy is
+//! used for a whole run.
+use std::ops::Range;
+use std::sync::Arc;
+
+pub trait SplitPolicy: Send + Sync + std::fmt::Debug {
+    /// How many pieces (including the one we keep) we want for `steal_counter` thieves.
+    fn pieces(&self, steal_counter: usize) -> usize;
+    /// Sizes of the pieces for `work` units of work, the first one is the piece we keep.
+    /// They add up to `work` and none of them is empty, so we might get fewer pieces than
+    /// `pieces` asks for.
+    fn sizes(&self, work: usize, steal_counter: usize) -> Vec<usize>;
+    /// Cut a range of indices into consecutive pieces according to `sizes`.
+    fn ranges(&self, range: Range<usize>, steal_counter: usize) -> Vec<Range<usize>> {
+        let mut start = range.start;
+        self.sizes(range.end - range.start, steal_counter)
+            .into_iter()
+            .map(|size| {
+                start += size;
+                start - size..start
+            })
+            .collect()
+    }
+}
+
+pub fn default_policy() -> Arc<dyn SplitPolicy> {
+    Arc::new(Equal)
+}
+
+// n pieces that differ by at most one
+fn equal_sizes(work: usize, n: usize) -> Vec<usize> {
+    let n = n.min(work).max(1);
+    (0..n)
+        .map(|i| work / n + if i < work % n { 1 } else { 0 })
+        .collect()
+}
+
+/// Always split in two halves, no matter how many thieves are waiting.
+#[derive(Debug)]
+pub struct Half;
+impl SplitPolicy for Half {
+    fn pieces(&self, _steal_counter: usize) -> usize {
+        2
+    }
+    fn sizes(&self, work: usize, _steal_counter: usize) -> Vec<usize> {
+        equal_sizes(work, 2)
+    }
+}
+
+/// One equal piece for every thief plus one for us. That's what the tasks did before.
+#[derive(Debug)]
+pub struct Equal;
+impl SplitPolicy for Equal {
+    fn pieces(&self, steal_counter: usize) -> usize {
+        steal_counter + 1
+    }
+    fn sizes(&self, work: usize, steal_counter: usize) -> Vec<usize> {
+        equal_sizes(work, self.pieces(steal_counter))
+    }
+}
+
+/// Like `Equal`, but the number of pieces depends on the work left: we never create pieces
+/// smaller than `min_work`, except that we always split in two to serve at least one thief.
+#[derive(Debug)]
+pub struct Proportional {
+    pub min_work: usize,
+}
+impl SplitPolicy for Proportional {
+    fn pieces(&self, steal_counter: usize) -> usize {
+        steal_counter + 1
+    }
+    fn sizes(&self, work: usize, steal_counter: usize) -> Vec<usize> {
+        let n = (work / self.min_work.max(1))
+            .max(2)
+            .min(self.pieces(steal_counter));
+        equal_sizes(work, n)
+    }
+}
+
+/// Every piece gets half of what's left: we keep 1/2, the first thief gets 1/4 and so on.
+/// The last thief gets the rest.
+#[derive(Debug)]
+pub struct Geometric;
+impl SplitPolicy for Geometric {
+    fn pieces(&self, steal_counter: usize) -> usize {
+        steal_counter + 1
+    }
+    fn sizes(&self, work: usize, steal_counter: usize) -> Vec<usize> {
+        let mut sizes = Vec::new();
+        let mut left = work;
+        while sizes.len() + 1 < self.pieces(steal_counter) && left >= 2 {
+            let size = (left + 1) / 2;
+            sizes.push(size);
+            left -= size;
+        }
+        sizes.push(left);
+        sizes
+    }
+}
+
+#[test]
+fn test_policies() {
+    use crate::points::{Point, Searcher};
+    use crate::task::Task;
+    let policies: Vec<Arc<dyn SplitPolicy>> = vec![
+        Arc::new(Half),
+        Arc::new(Equal),
+        Arc::new(Proportional { min_work: 3 }),
+        Arc::new(Geometric),
+    ];
+    for policy in &policies {
+        for work in 1..20 {
+            for steal_counter in 1..6 {
+                let sizes = policy.sizes(work, steal_counter);
+                assert_eq!(sizes.iter().sum::<usize>(), work);
+                assert!(sizes.iter().all(|&s| s > 0));
+                assert!(sizes.len() <= policy.pieces(steal_counter));
+                assert!(work < 2 || sizes.len() >= 2);
+            }
+        }
+        let ranges = policy.ranges(5..17, 3);
+        assert_eq!(ranges.first().unwrap().start, 5);
+        assert_eq!(ranges.last().unwrap().end, 17);
+        assert!(ranges.windows(2).all(|w| w[0].end == w[1].start));
+    }
+    assert_eq!(Geometric.sizes(16, 3), vec![8, 4, 2, 2]);
+
+    // the searcher gives away the pieces its policy asks for
+    let points = Point::create_random_points(16);
+    for policy in policies {
+        let mut s = Searcher::new(&points).with_policy(policy.clone());
+        let mut pieces = 0;
+        s.split(|tasks| pieces = tasks.len(), 3);
+        assert_eq!(pieces, policy.sizes(16, 3).len());
+    }
+}
@@ -0,0 +1,246 @@
+//! Finding idle threads without steal requests, so the adaptive tasks also run on stock rayon.
+//! Every pool counts its threads (in the start and exit handlers), how many of them are busy
+//! running a task (not just waiting for the other pieces of a split) and how many pieces of
+//! split tasks nobody started yet. A task splits if there are more idle threads than pieces
+//! waiting for them. Pools choose how their tasks find idle threads when they're built, see
+//! `crate::rayon::get_polling_thread_pool`.
+use crossbeam_utils::CachePadded;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// How a task finds out that other threads want work.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Detection {
+    /// Thieves send steal requests, needs the steal callback of the rayon fork (or our own pool)
+    StealRequests,
+    /// We look at how many threads of the pool are idle
+    Polling,
+}
+
+impl fmt::Display for Detection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Detection::StealRequests => write!(f, "requests"),
+            Detection::Polling => write!(f, "polling"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Activity {
+    threads: CachePadded<AtomicUsize>,
+    busy: CachePadded<AtomicUsize>,
+    pending: CachePadded<AtomicUsize>,
+}
+
+lazy_static! {
+    // for pools that we didn't build ourselves, they don't have any threads we know about
+    static ref GLOBAL: Arc<Activity> = Arc::new(Activity::default());
+}
+thread_local! {
+    static ACTIVITY: RefCell<Option<Arc<Activity>>> = RefCell::new(None);
+    // how many tasks this thread is running inside each other
+    static DEPTH: Cell<usize> = Cell::new(0);
+    // the detection of our pool, tasks look at it all the time
+    static POLLING: Cell<bool> = Cell::new(false);
+}
+
+fn with_activity<R>(f: impl FnOnce(&Arc<Activity>) -> R) -> R {
+    ACTIVITY.with(|a| match &*a.borrow() {
+        Some(activity) => f(activity),
+        None => f(&GLOBAL),
+    })
+}
+
+fn polling() -> bool {
+    POLLING.with(|p| p.get())
+}
+
+/// How tasks in the pool we run in find idle threads.
+pub fn detection() -> Detection {
+    if polling() {
+        Detection::Polling
+    } else {
+        Detection::StealRequests
+    }
+}
+
+// Give this to the start handler of a pool, and `exit_handler` to its exit handler
+pub(crate) fn start_handler(detection: Detection) -> impl Fn(usize) + Send + Sync {
+    let activity = Arc::new(Activity::default());
+    let polling = detection == Detection::Polling;
+    move |_| {
+        activity.threads.fetch_add(1, Ordering::Relaxed);
+        ACTIVITY.with(|a| *a.borrow_mut() = Some(activity.clone()));
+        POLLING.with(|p| p.set(polling));
+    }
+}
+pub(crate) fn exit_handler(_index: usize) {
+    with_activity(|activity| activity.threads.fetch_sub(1, Ordering::Relaxed));
+    ACTIVITY.with(|a| *a.borrow_mut() = None);
+    POLLING.with(|p| p.set(false));
+}
+
+/// How many threads of our pool have nothing to do and aren't about to get a piece of work
+/// already, at most all the others.
+pub fn idle_threads() -> usize {
+    if crate::rayon::current_thread_index().is_none() {
+        return 0;
+    }
+    with_activity(|activity| {
+        let threads = activity.threads.load(Ordering::Relaxed);
+        let busy = activity.busy.load(Ordering::Relaxed);
+        let pending = activity.pending.load(Ordering::Relaxed);
+        let idle = threads.saturating_sub(busy).saturating_sub(pending);
+        std::cmp::min(idle, threads.saturating_sub(1))
+    })
+}
+
+// The guards below only keep count in pools that poll, the others don't get one at all, so
+// steal requests don't pay for the atomics.
+
+// This thread runs a task as long as we're around, nested tasks only count once
+pub(crate) struct Busy;
+impl Busy {
+    pub(crate) fn enter() -> Option<Self> {
+        if !polling() {
+            return None;
+        }
+        DEPTH.with(|depth| {
+            if depth.get() == 0 {
+                with_activity(|activity| activity.busy.fetch_add(1, Ordering::Relaxed));
+            }
+            depth.set(depth.get() + 1);
+        });
+        Some(Busy)
+    }
+}
+impl Drop for Busy {
+    fn drop(&mut self) {
+        DEPTH.with(|depth| {
+            depth.set(depth.get() - 1);
+            if depth.get() == 0 {
+                with_activity(|activity| activity.busy.fetch_sub(1, Ordering::Relaxed));
+            }
+        });
+    }
+}
+
+// Until it's dropped this thread isn't busy, even if it's in the middle of a task. That's for
+// waiting on the other pieces of a split, the ones we run ourselves enter `Busy` again.
+pub(crate) struct Idle(usize);
+impl Idle {
+    pub(crate) fn enter() -> Option<Self> {
+        if !polling() {
+            return None;
+        }
+        let depth = DEPTH.with(|depth| depth.replace(0));
+        if depth > 0 {
+            with_activity(|activity| activity.busy.fetch_sub(1, Ordering::Relaxed));
+        }
+        Some(Idle(depth))
+    }
+}
+impl Drop for Idle {
+    fn drop(&mut self) {
+        if self.0 > 0 {
+            with_activity(|activity| activity.busy.fetch_add(1, Ordering::Relaxed));
+        }
+        DEPTH.with(|depth| depth.set(self.0));
+    }
+}
+
+// A piece of a split task that another thread can take, until somebody starts (or drops) it
+pub(crate) struct Pending(Arc<Activity>);
+impl Pending {
+    pub(crate) fn new() -> Option<Self> {
+        if !polling() {
+            return None;
+        }
+        let activity = with_activity(|activity| activity.clone());
+        activity.pending.fetch_add(1, Ordering::Relaxed);
+        Some(Pending(activity))
+    }
+}
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.pending.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn test_polling() {
+    use crate::rayon::{get_custom_thread_pool, get_polling_thread_pool, AdaptivePoolBuilder};
+    use crate::statistics;
+    use crate::task::Task;
+    let pool = get_polling_thread_pool(4);
+    assert_eq!(pool.install(detection), Detection::Polling);
+    // the threads count themselves once they're started
+    while pool.install(idle_threads) < 3 {
+        std::thread::yield_now();
+    }
+    // the other threads are idle, but we don't count ourselves
+    pool.install(|| {
+        let _busy = Busy::enter();
+        assert_eq!(idle_threads(), 3);
+        let _pending = Pending::new();
+        assert_eq!(idle_threads(), 2);
+        // waiting for the other half of a join, until we run a task again
+        let _idle = Idle::enter();
+        assert_eq!(idle_threads(), 3);
+        let _busy = Busy::enter();
+        assert_eq!(idle_threads(), 2);
+    });
+    assert_eq!(idle_threads(), 0);
+    // pools with steal requests don't count anything
+    let requests = crate::rayon::get_builtin_thread_pool(2, 8);
+    assert!(requests.install(|| Busy::enter().is_none() && Pending::new().is_none()));
+    // only the fork sends steal requests
+    let expected = if cfg!(feature = "steal-callback") {
+        Detection::StealRequests
+    } else {
+        Detection::Polling
+    };
+    assert_eq!(get_custom_thread_pool(2, 8).install(detection), expected);
+
+    // Takes longer for smaller numbers. The thread that splits goes on with the right half, so
+    // it's done way before the thief
+    struct Sleeper {
+        range: std::ops::Range<u64>,
+    }
+    impl Task for Sleeper {
+        fn step(&mut self) {
+            std::thread::sleep(std::time::Duration::from_micros((100 - self.range.start) * 20));
+            self.range.start += 1;
+        }
+        fn can_split(&self) -> bool {
+            self.range.end - self.range.start > 1
+        }
+        fn is_finished(&self) -> bool {
+            self.range.start == self.range.end
+        }
+        fn split(&mut self, mut runner: impl FnMut(&mut Vec<&mut Self>), _steal_counter: usize) {
+            let half = (self.range.end - self.range.start) / 2 + self.range.start;
+            let mut other = Sleeper {
+                range: half..self.range.end,
+            };
+            self.range.end = half;
+            runner(&mut vec![self, &mut other]);
+        }
+        fn fuse(&mut self, _other: &mut Self) {}
+    }
+    let pool = AdaptivePoolBuilder::new()
+        .num_threads(2)
+        .detection(Detection::Polling)
+        .statistics(true)
+        .build();
+    while pool.install(idle_threads) < 1 {
+        std::thread::yield_now();
+    }
+    pool.install(|| Sleeper { range: 0..100 }.run());
+    // The first split is for the other thread. Once we're done with our half we wait for the
+    // thief, but we're idle, so it splits again for us (and then we for it)
+    assert!(statistics::of(&pool).total().splits > 1);
+}
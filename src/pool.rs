@@ -0,0 +1,401 @@
+//! A small work-stealing pool of our own, so adaptive tasks don't need the rayon fork with its
+//! steal callback. Every worker has a deque of jobs: it pushes and pops at the back, thieves
+//! take from the front. Workers without work call the steal callback, just like the fork does,
+//! so with the one from `crate::rayon` running adaptive tasks split for them.
+//! `crate::rayon::join` and friends check at runtime if they run in one of these pools.
+use std::cell::{Cell, UnsafeCell};
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// how often an idle worker tries to find work before it goes to sleep for a bit
+const IDLE_ROUNDS: usize = 64;
+
+// A job somewhere on the stack of a thread that waits for it to be done
+#[derive(Clone, Copy)]
+struct JobRef {
+    pointer: *const (),
+    execute: unsafe fn(*const ()),
+}
+// Safety: the jobs behind it are Send, and they live until they're done
+unsafe impl Send for JobRef {}
+
+impl JobRef {
+    unsafe fn execute(self) {
+        (self.execute)(self.pointer)
+    }
+}
+
+struct StackJob<F, R> {
+    func: UnsafeCell<Option<F>>,
+    result: UnsafeCell<Option<thread::Result<R>>>,
+    done: AtomicBool,
+    // gets woken up once we're done, if it's not a worker
+    waiter: Option<thread::Thread>,
+}
+
+impl<F, R> StackJob<F, R>
+where
+    F: FnOnce() -> R + Send,
+    R: Send,
+{
+    fn new(func: F, waiter: Option<thread::Thread>) -> Self {
+        StackJob {
+            func: UnsafeCell::new(Some(func)),
+            result: UnsafeCell::new(None),
+            done: AtomicBool::new(false),
+            waiter,
+        }
+    }
+    fn as_job_ref(&self) -> JobRef {
+        JobRef {
+            pointer: self as *const Self as *const (),
+            execute: Self::execute,
+        }
+    }
+    unsafe fn execute(this: *const ()) {
+        let this = &*(this as *const Self);
+        let func = (*this.func.get()).take().unwrap();
+        *this.result.get() = Some(panic::catch_unwind(AssertUnwindSafe(func)));
+        // once done is set the job can be gone, so don't touch it after that
+        let waiter = this.waiter.clone();
+        this.done.store(true, Ordering::Release);
+        if let Some(waiter) = waiter {
+            waiter.unpark();
+        }
+    }
+    fn is_done(&self) -> bool {
+        self.done.load(Ordering::Acquire)
+    }
+    fn into_result(self) -> R {
+        match self.result.into_inner().unwrap() {
+            Ok(result) => result,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+    // nobody took it, so we run it ourselves
+    fn run_inline(self) -> R {
+        (self.func.into_inner().unwrap())()
+    }
+}
+
+struct Registry {
+    deques: Vec<Mutex<VecDeque<JobRef>>>,
+    // jobs from outside of the pool
+    injected: Mutex<VecDeque<JobRef>>,
+    sleep: Condvar,
+    terminate: AtomicBool,
+    steal_callback: Option<Box<dyn Fn(usize) -> Option<()> + Send + Sync>>,
+}
+
+impl Registry {
+    fn num_threads(&self) -> usize {
+        self.deques.len()
+    }
+    fn push(&self, index: usize, job: JobRef) {
+        self.deques[index].lock().unwrap().push_back(job);
+    }
+    // take back our job if it's still on top of our deque
+    fn take_back(&self, index: usize, job: JobRef) -> bool {
+        let mut deque = self.deques[index].lock().unwrap();
+        // the stack address alone tells jobs apart
+        if deque.back().map(|j| j.pointer) == Some(job.pointer) {
+            deque.pop_back();
+            true
+        } else {
+            false
+        }
+    }
+    fn steal(&self, index: usize) -> Option<JobRef> {
+        let n = self.num_threads();
+        let start = rand::random::<usize>() % n;
+        (0..n)
+            .map(|i| (start + i) % n)
+            .filter(|&victim| victim != index)
+            .find_map(|victim| self.deques[victim].lock().unwrap().pop_front())
+    }
+    fn find_work(&self, index: usize) -> Option<JobRef> {
+        let own = self.deques[index].lock().unwrap().pop_back();
+        own.or_else(|| self.injected.lock().unwrap().pop_front())
+            .or_else(|| self.steal(index))
+    }
+    // ask a random victim to split its task for us
+    fn request_work(&self, index: usize) {
+        let n = self.num_threads();
+        if n < 2 {
+            return;
+        }
+        if let Some(steal_callback) = &self.steal_callback {
+            let victim = (index + 1 + rand::random::<usize>() % (n - 1)) % n;
+            steal_callback(victim);
+        }
+    }
+    fn wait_until(&self, index: usize, done: impl Fn() -> bool) {
+        while !done() {
+            if let Some(job) = self.steal(index) {
+                unsafe { job.execute() };
+            } else {
+                self.request_work(index);
+                thread::yield_now();
+            }
+        }
+    }
+    fn main_loop(&self, index: usize) {
+        let mut idle = 0;
+        while !self.terminate.load(Ordering::Acquire) {
+            if let Some(job) = self.find_work(index) {
+                unsafe { job.execute() };
+                idle = 0;
+            } else if idle < IDLE_ROUNDS {
+                self.request_work(index);
+                idle += 1;
+            } else {
+                let injected = self.injected.lock().unwrap();
+                if injected.is_empty() && !self.terminate.load(Ordering::Acquire) {
+                    let _ = self
+                        .sleep
+                        .wait_timeout(injected, Duration::from_millis(1))
+                        .unwrap();
+                }
+                idle = 0;
+            }
+        }
+    }
+}
+
+thread_local! {
+    // the pool we're a worker of and our index in it
+    static WORKER: Cell<Option<(*const Registry, usize)>> = Cell::new(None);
+}
+
+fn current_worker() -> Option<(&'static Registry, usize)> {
+    // Safety: the worker thread keeps its registry alive as long as it runs
+    WORKER.with(|w| w.get().map(|(registry, index)| (unsafe { &*registry }, index)))
+}
+
+pub fn current_thread_index() -> Option<usize> {
+    current_worker().map(|(_, index)| index)
+}
+
+pub fn current_num_threads() -> Option<usize> {
+    current_worker().map(|(registry, _)| registry.num_threads())
+}
+
+/// Runs both closures, `oper_b` can get stolen. Only works on a worker thread of a pool.
+pub fn join<A, B, RA, RB>(oper_a: A, oper_b: B) -> (RA, RB)
+where
+    A: FnOnce() -> RA + Send,
+    B: FnOnce() -> RB + Send,
+    RA: Send,
+    RB: Send,
+{
+    let (registry, index) = current_worker().expect("not in a pool");
+    let job_b = StackJob::new(oper_b, None);
+    let job_ref = job_b.as_job_ref();
+    registry.push(index, job_ref);
+    let result_a = panic::catch_unwind(AssertUnwindSafe(oper_a));
+    let result_b = if registry.take_back(index, job_ref) {
+        match result_a {
+            Ok(_) => job_b.run_inline(),
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    } else {
+        // somebody stole it, so it's still using our stack even if a panicked
+        registry.wait_until(index, || job_b.is_done());
+        job_b.into_result()
+    };
+    match result_a {
+        Ok(result_a) => (result_a, result_b),
+        Err(payload) => panic::resume_unwind(payload),
+    }
+}
+
+pub struct ThreadPool {
+    registry: Arc<Registry>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    /// Run `op` on a worker of the pool and wait for it.
+    pub fn install<OP, R>(&self, op: OP) -> R
+    where
+        OP: FnOnce() -> R + Send,
+        R: Send,
+    {
+        if let Some((registry, _)) = current_worker() {
+            if std::ptr::eq(registry, &*self.registry) {
+                return op();
+            }
+        }
+        let job = StackJob::new(op, Some(thread::current()));
+        self.registry
+            .injected
+            .lock()
+            .unwrap()
+            .push_back(job.as_job_ref());
+        self.registry.sleep.notify_all();
+        while !job.is_done() {
+            thread::park();
+        }
+        job.into_result()
+    }
+    pub fn current_num_threads(&self) -> usize {
+        self.registry.num_threads()
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.registry.terminate.store(true, Ordering::Release);
+        self.registry.sleep.notify_all();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ThreadPoolBuilder {
+    num_threads: usize,
+    steal_callback: Option<Box<dyn Fn(usize) -> Option<()> + Send + Sync>>,
+    start_handler: Option<Box<dyn Fn(usize) + Send + Sync>>,
+    exit_handler: Option<Box<dyn Fn(usize) + Send + Sync>>,
+    thread_name: Option<Box<dyn FnMut(usize) -> String>>,
+}
+
+impl ThreadPoolBuilder {
+    pub fn new() -> Self {
+        ThreadPoolBuilder::default()
+    }
+    /// 0 is one thread per cpu
+    pub fn num_threads(mut self, num_threads: usize) -> Self {
+        self.num_threads = num_threads;
+        self
+    }
+    /// Gets called with a victim when a worker doesn't find any work, like in the rayon fork.
+    pub fn steal_callback<F>(mut self, steal_callback: F) -> Self
+    where
+        F: Fn(usize) -> Option<()> + Send + Sync + 'static,
+    {
+        self.steal_callback = Some(Box::new(steal_callback));
+        self
+    }
+    /// Every worker calls this with its index before it looks for work, like in rayon.
+    pub fn start_handler(mut self, start_handler: impl Fn(usize) + Send + Sync + 'static) -> Self {
+        self.start_handler = Some(Box::new(start_handler));
+        self
+    }
+    pub fn exit_handler(mut self, exit_handler: impl Fn(usize) + Send + Sync + 'static) -> Self {
+        self.exit_handler = Some(Box::new(exit_handler));
+        self
+    }
+    pub fn thread_name(mut self, name: impl FnMut(usize) -> String + 'static) -> Self {
+        self.thread_name = Some(Box::new(name));
+        self
+    }
+    pub fn build(self) -> ThreadPool {
+        let num_threads = if self.num_threads == 0 {
+            num_cpus::get()
+        } else {
+            self.num_threads
+        };
+        let registry = Arc::new(Registry {
+            deques: (0..num_threads)
+                .map(|_| Mutex::new(VecDeque::new()))
+                .collect(),
+            injected: Mutex::new(VecDeque::new()),
+            sleep: Condvar::new(),
+            terminate: AtomicBool::new(false),
+            steal_callback: self.steal_callback,
+        });
+        let start_handler: Option<Arc<dyn Fn(usize) + Send + Sync>> =
+            self.start_handler.map(Arc::from);
+        let exit_handler: Option<Arc<dyn Fn(usize) + Send + Sync>> =
+            self.exit_handler.map(Arc::from);
+        let mut thread_name = self.thread_name;
+        let threads = (0..num_threads)
+            .map(|index| {
+                let registry = registry.clone();
+                let start_handler = start_handler.clone();
+                let exit_handler = exit_handler.clone();
+                let mut builder = thread::Builder::new();
+                if let Some(name) = &mut thread_name {
+                    builder = builder.name(name(index));
+                }
+                builder
+                    .spawn(move || {
+                        if let Some(start_handler) = start_handler {
+                            start_handler(index);
+                        }
+                        WORKER.with(|w| w.set(Some((&*registry as *const Registry, index))));
+                        registry.main_loop(index);
+                        WORKER.with(|w| w.set(None));
+                        if let Some(exit_handler) = exit_handler {
+                            exit_handler(index);
+                        }
+                    })
+                    .unwrap()
+            })
+            .collect();
+        ThreadPool { registry, threads }
+    }
+}
+
+#[test]
+fn test_pool() {
+    use crate::points::{Point, Searcher};
+    use crate::task::Task;
+    use std::collections::HashSet;
+    use std::sync::atomic::AtomicUsize;
+    // idle workers ask other workers for work
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = requests.clone();
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(3)
+        .steal_callback(move |victim| {
+            assert!(victim < 3 && Some(victim) != current_thread_index());
+            counter.fetch_add(1, Ordering::Relaxed);
+            None
+        })
+        .build();
+    thread::sleep(Duration::from_millis(10));
+    assert!(requests.load(Ordering::Relaxed) > 0);
+
+    // the halves of a join get stolen by the other workers
+    fn spread(depth: usize) -> HashSet<usize> {
+        if depth == 0 {
+            thread::sleep(Duration::from_millis(1));
+            return std::iter::once(current_thread_index().unwrap()).collect();
+        }
+        let (mut a, b) = join(|| spread(depth - 1), || spread(depth - 1));
+        a.extend(b);
+        a
+    }
+    assert!(pool.install(|| spread(5)).len() > 1);
+
+    // and adaptive tasks split for them
+    let pool = crate::rayon::AdaptivePoolBuilder::new()
+        .num_threads(4)
+        .strategy(8)
+        .statistics(true)
+        .builtin()
+        .build();
+    let points = Point::create_random_points(3000);
+    let (_, stats) = pool.install(|| crate::statistics::measure(|| Searcher::new(&points).run()));
+    assert!(stats.total().splits > 0);
+
+    // a panic in a stolen or not stolen half of a join comes back out of install
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        pool.install(|| {
+            crate::rayon::join(
+                || (0..1000).sum::<u64>(),
+                || -> u64 { panic!("in the pool") },
+            )
+        })
+    }));
+    assert!(result.is_err());
+    assert_eq!(pool.install(|| crate::rayon::join(|| 1, || 2)), (1, 2));
+}
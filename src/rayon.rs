@@ -1,34 +1,367 @@
-use crate::steal;
+use crate::polling::{self, Detection};
+use crate::pool;
+use crate::rayon_crate;
+use crate::statistics;
+use crate::steal::{self, StealStrategy};
+use crate::task;
+use crate::topology::Topology;
 
-#[cfg(feature = "logs")]
-pub fn get_thread_pool() -> rayon_logs::ThreadPool {
-    rayon_logs::ThreadPoolBuilder::new()
-        .steal_callback(|x| steal::optimized_steal(x))
+/// One thread per cpu, thieves wait for the victims as long as `StealStrategy::Optimized` says.
+pub fn get_thread_pool() -> AdaptivePool {
+    AdaptivePoolBuilder::new().build()
+}
+/// `strategy` is how long thieves wait, a number is the old number of backoffs.
+pub fn get_custom_thread_pool(
+    num_threads: usize,
+    strategy: impl Into<StealStrategy>,
+) -> AdaptivePool {
+    AdaptivePoolBuilder::new()
+        .num_threads(num_threads)
+        .strategy(strategy)
         .build()
-        .unwrap()
 }
-#[cfg(not(feature = "logs"))]
-pub fn get_thread_pool() -> rayon::ThreadPool {
-    rayon::ThreadPoolBuilder::new()
-        .steal_callback(|x| steal::optimized_steal(x))
+/// Like `get_custom_thread_pool`, but thieves don't wait as long for victims in another group
+/// of `topology`, use `Topology::detect` for the one of this machine.
+pub fn get_topology_thread_pool(
+    num_threads: usize,
+    strategy: impl Into<StealStrategy>,
+    topology: Topology,
+) -> AdaptivePool {
+    AdaptivePoolBuilder::new()
+        .num_threads(num_threads)
+        .strategy(strategy)
+        .topology(topology)
         .build()
-        .unwrap()
 }
-#[cfg(feature = "logs")]
-pub fn get_custom_thread_pool(num_threads: usize, backoffs: usize) -> rayon_logs::ThreadPool {
-    rayon_logs::ThreadPoolBuilder::new()
-        .steal_callback(move |x| steal::steal(backoffs,x ))
+/// A rayon pool without steal requests, tasks split when they see idle threads. That works
+/// on stock rayon as well.
+pub fn get_polling_thread_pool(num_threads: usize) -> AdaptivePool {
+    AdaptivePoolBuilder::new()
         .num_threads(num_threads)
+        .detection(Detection::Polling)
         .build()
-        .unwrap()
 }
-#[cfg(not(feature = "logs"))]
-pub fn get_custom_thread_pool(num_threads: usize, backoffs: usize) -> rayon::ThreadPool {
-    rayon::ThreadPoolBuilder::new()
-        .steal_callback(move |x| steal::steal(backoffs, x))
+
+/// Builds the pools we run adaptive tasks in. That's a rayon pool whose threads get their own
+/// steal requests, statistics, idle counters and multisplit setting, or our own pool with the
+/// same (`builtin`). With the `logs` feature it can also be a rayon_logs pool (`logs`), which
+/// only knows about the number of threads and the steal strategy.
+/// Only the rayon fork (feature `steal-callback`) sends steal requests, so on stock rayon the
+/// rayon pools poll for idle threads unless we ask for something else.
+pub struct AdaptivePoolBuilder {
+    num_threads: usize,
+    // None means nobody sends steal requests
+    strategy: Option<StealStrategy>,
+    topology: Option<Topology>,
+    // None is steal requests where the pool can send them, polling otherwise
+    detection: Option<Detection>,
+    statistics: Option<bool>,
+    multisplit: Option<bool>,
+    thread_name: Option<Box<dyn FnMut(usize) -> String>>,
+    builtin: bool,
+    #[cfg(feature = "logs")]
+    logs: bool,
+}
+
+impl Default for AdaptivePoolBuilder {
+    fn default() -> Self {
+        AdaptivePoolBuilder {
+            num_threads: 0,
+            strategy: Some(StealStrategy::Optimized),
+            topology: None,
+            detection: None,
+            statistics: None,
+            multisplit: None,
+            thread_name: None,
+            builtin: false,
+            #[cfg(feature = "logs")]
+            logs: false,
+        }
+    }
+}
+
+impl AdaptivePoolBuilder {
+    pub fn new() -> Self {
+        AdaptivePoolBuilder::default()
+    }
+    /// 0 is rayon's default
+    pub fn num_threads(mut self, num_threads: usize) -> Self {
+        self.num_threads = num_threads;
+        self
+    }
+    /// How long thieves wait for an answer, a number is the old number of backoffs.
+    pub fn strategy(mut self, strategy: impl Into<StealStrategy>) -> Self {
+        self.strategy = Some(strategy.into());
+        self
+    }
+    /// Tasks never split, unless they poll for idle threads.
+    pub fn without_steal_requests(mut self) -> Self {
+        self.strategy = None;
+        self
+    }
+    /// Thieves don't wait as long for victims in another group.
+    pub fn topology(mut self, topology: Topology) -> Self {
+        self.topology = Some(topology);
+        self
+    }
+    /// With `Detection::Polling` there's no steal callback, so it's just stock rayon.
+    pub fn detection(mut self, detection: Detection) -> Self {
+        self.detection = Some(detection);
+        self
+    }
+    /// Collect statistics in this pool or not, whatever `statistics::set_enabled` says.
+    pub fn statistics(mut self, statistics: bool) -> Self {
+        self.statistics = Some(statistics);
+        self
+    }
+    /// Split tasks into one piece per thief or just in two, whatever `task::set_multisplit`
+    /// says if we don't choose.
+    pub fn multisplit(mut self, multisplit: bool) -> Self {
+        self.multisplit = Some(multisplit);
+        self
+    }
+    pub fn thread_name(mut self, name: impl FnMut(usize) -> String + 'static) -> Self {
+        self.thread_name = Some(Box::new(name));
+        self
+    }
+    /// Run on our own pool (see `crate::pool`) instead of rayon, it sends steal requests
+    /// without the steal callback of the rayon fork.
+    pub fn builtin(mut self) -> Self {
+        self.builtin = true;
+        self
+    }
+    /// Build a rayon_logs pool, so we can log runs with `AdaptivePool::logging_install`. It has
+    /// no start handler, so building panics if we asked for anything but the number of
+    /// threads and the steal strategy.
+    #[cfg(feature = "logs")]
+    pub fn logs(mut self) -> Self {
+        self.logs = true;
+        self
+    }
+    // what the pool ends up with, panics if it can't do what we asked for
+    fn resolved_detection(&self) -> Detection {
+        let steal_requests = self.builtin || cfg!(feature = "steal-callback");
+        match self.detection {
+            Some(Detection::StealRequests) => {
+                assert!(
+                    steal_requests,
+                    "stock rayon can't send steal requests, use the `steal-callback` feature, \
+                     `builtin` or `Detection::Polling`"
+                );
+                Detection::StealRequests
+            }
+            Some(detection) => detection,
+            None if steal_requests => Detection::StealRequests,
+            None => Detection::Polling,
+        }
+    }
+    // the steal callback, if we need one
+    fn steal_callback(&mut self) -> Option<impl Fn(usize) -> Option<()> + Send + Sync> {
+        let strategy = match (self.resolved_detection(), self.strategy) {
+            (Detection::StealRequests, Some(strategy)) => strategy,
+            _ => return None,
+        };
+        let topology = self.topology.take();
+        Some(move |victim| match &topology {
+            Some(topology) => steal::steal_with_topology(strategy, topology, victim),
+            None => steal::steal_with(strategy, victim),
+        })
+    }
+    pub fn build(mut self) -> AdaptivePool {
+        #[cfg(feature = "logs")]
+        {
+            if self.logs {
+                return self.build_logs();
+            }
+        }
+        let num_threads = if self.num_threads == 0 {
+            default_num_threads()
+        } else {
+            self.num_threads
+        };
+        let start_handler = start_handler(
+            num_threads,
+            self.resolved_detection(),
+            self.statistics,
+            self.multisplit,
+        );
+        let steal_callback = self.steal_callback();
+        let pool = if self.builtin {
+            let mut builder = pool::ThreadPoolBuilder::new()
+                .num_threads(num_threads)
+                .start_handler(start_handler)
+                .exit_handler(polling::exit_handler);
+            if let Some(steal_callback) = steal_callback {
+                builder = builder.steal_callback(steal_callback);
+            }
+            if let Some(name) = self.thread_name {
+                builder = builder.thread_name(name);
+            }
+            Backend::Builtin(builder.build())
+        } else {
+            let mut builder = rayon_crate::ThreadPoolBuilder::new()
+                .num_threads(num_threads)
+                .start_handler(start_handler)
+                .exit_handler(polling::exit_handler);
+            // only the fork has one, see `resolved_detection` for what stock rayon does instead
+            #[cfg(feature = "steal-callback")]
+            {
+                if let Some(steal_callback) = steal_callback {
+                    builder = builder.steal_callback(steal_callback);
+                }
+            }
+            if let Some(name) = self.thread_name {
+                builder = builder.thread_name(name);
+            }
+            Backend::Rayon(builder.build().unwrap())
+        };
+        AdaptivePool { pool }
+    }
+    #[cfg(feature = "logs")]
+    fn build_logs(mut self) -> AdaptivePool {
+        // no start handler, so its threads share the global steal requests and statistics
+        assert!(!self.builtin, "a pool can't be builtin and log");
+        assert!(
+            self.resolved_detection() == Detection::StealRequests,
+            "rayon_logs pools don't count their idle threads, so they can't poll"
+        );
+        assert!(
+            self.statistics.is_none() && self.multisplit.is_none(),
+            "rayon_logs pools can't have statistics or multisplit of their own"
+        );
+        assert!(
+            self.thread_name.is_none(),
+            "rayon_logs names its threads itself"
+        );
+        assert!(
+            self.num_threads <= steal::global_num_threads(),
+            "the global steal requests are only for {} threads",
+            steal::global_num_threads()
+        );
+        let mut builder = rayon_logs::ThreadPoolBuilder::new();
+        if self.num_threads != 0 {
+            builder = builder.num_threads(self.num_threads);
+        }
+        if let Some(steal_callback) = self.steal_callback() {
+            builder = builder.steal_callback(steal_callback);
+        }
+        AdaptivePool {
+            pool: Backend::Logs(builder.build().unwrap()),
+        }
+    }
+}
+
+enum Backend {
+    Rayon(rayon_crate::ThreadPool),
+    Builtin(pool::ThreadPool),
+    #[cfg(feature = "logs")]
+    Logs(rayon_logs::ThreadPool),
+}
+
+/// A pool built by `AdaptivePoolBuilder`, whichever kind it is.
+pub struct AdaptivePool {
+    pool: Backend,
+}
+
+impl AdaptivePool {
+    pub fn install<OP, R>(&self, op: OP) -> R
+    where
+        OP: FnOnce() -> R + Send,
+        R: Send,
+    {
+        match &self.pool {
+            Backend::Rayon(pool) => pool.install(op),
+            Backend::Builtin(pool) => pool.install(op),
+            #[cfg(feature = "logs")]
+            Backend::Logs(pool) => pool.install(op),
+        }
+    }
+    /// Only for pools built with `AdaptivePoolBuilder::logs`.
+    #[cfg(feature = "logs")]
+    pub fn logging_install<OP, R>(&self, op: OP) -> (R, rayon_logs::RunLog)
+    where
+        OP: FnOnce() -> R + Send,
+        R: Send,
+    {
+        match &self.pool {
+            Backend::Logs(pool) => pool.logging_install(op),
+            _ => panic!("only pools built with `AdaptivePoolBuilder::logs` log"),
+        }
+    }
+}
+
+/// Our own work-stealing pool, doesn't need the steal callback of the rayon fork. Tasks run
+/// in it just like in the rayon ones, `join` checks which kind of pool it's in.
+pub fn get_builtin_thread_pool(
+    num_threads: usize,
+    strategy: impl Into<StealStrategy>,
+) -> AdaptivePool {
+    AdaptivePoolBuilder::new()
         .num_threads(num_threads)
+        .strategy(strategy)
+        .builtin()
         .build()
-        .unwrap()
+}
+
+/// Every rayon pool running adaptive tasks should be built from this, so its threads get
+/// their own steal requests and statistics. 0 threads means rayon's default. On stock rayon
+/// nobody sends steal requests, so tasks poll for idle threads.
+pub fn thread_pool_builder(num_threads: usize) -> rayon_crate::ThreadPoolBuilder {
+    let num_threads = if num_threads == 0 {
+        default_num_threads()
+    } else {
+        num_threads
+    };
+    rayon_crate::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .start_handler(start_handler(
+            num_threads,
+            if cfg!(feature = "steal-callback") {
+                Detection::StealRequests
+            } else {
+                Detection::Polling
+            },
+            None,
+            None,
+        ))
+        .exit_handler(polling::exit_handler)
+}
+
+#[cfg(feature = "logs")]
+thread_local! {
+    // only the threads of our own pools, the rayon_logs ones don't have a start handler
+    static ADAPTIVE_THREAD: std::cell::Cell<bool> = std::cell::Cell::new(false);
+}
+
+// Sets up the state of a thread in one of our pools, with `polling::exit_handler` as the exit
+// handler
+fn start_handler(
+    num_threads: usize,
+    detection: Detection,
+    statistics: Option<bool>,
+    multisplit: Option<bool>,
+) -> impl Fn(usize) + Send + Sync {
+    let steal_handler = steal::start_handler(num_threads);
+    let statistics_handler = statistics::start_handler(num_threads, statistics);
+    let polling_handler = polling::start_handler(detection);
+    let multisplit_handler = task::start_handler(multisplit);
+    move |index| {
+        steal_handler(index);
+        statistics_handler(index);
+        polling_handler(index);
+        multisplit_handler(index);
+        #[cfg(feature = "logs")]
+        ADAPTIVE_THREAD.with(|a| a.set(true));
+    }
+}
+
+// How many threads rayon would use, we need to know it up front
+fn default_num_threads() -> usize {
+    std::env::var("RAYON_NUM_THREADS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or_else(num_cpus::get)
 }
 
 // quick abstraction that allow to switch easily between rayon and rayon_logs
@@ -39,10 +372,25 @@ where
     RA: Send,
     RB: Send,
 {
+    if pool::current_thread_index().is_some() {
+        return pool::join(oper_a, oper_b);
+    }
     #[cfg(feature = "logs")]
-    return rayon_logs::join(|| oper_a(), || oper_b());
-    #[cfg(not(feature = "logs"))]
-    return rayon::join(|| oper_a(), || oper_b());
+    {
+        if !ADAPTIVE_THREAD.with(|a| a.get()) {
+            return rayon_logs::join(|| oper_a(), || oper_b());
+        }
+    }
+    rayon_crate::join(|| oper_a(), || oper_b())
+}
+
+/// Our index in the pool we run in, whichever kind it is.
+pub fn current_thread_index() -> Option<usize> {
+    pool::current_thread_index().or_else(rayon_crate::current_thread_index)
+}
+
+pub fn current_num_threads() -> usize {
+    pool::current_num_threads().unwrap_or_else(rayon_crate::current_num_threads)
 }
 
 #[allow(unused)]
@@ -55,3 +403,57 @@ where
     #[cfg(not(feature = "logs"))]
     return op();
 }
+
+#[test]
+fn test_pool_builder() {
+    use crate::points::{Point, Searcher};
+    use crate::task::Task;
+    let pool = AdaptivePoolBuilder::new()
+        .num_threads(3)
+        .strategy(StealStrategy::Spin(64))
+        .topology(Topology::uniform(3, 2))
+        .statistics(true)
+        .multisplit(!task::multisplit())
+        .thread_name(|i| format!("adaptive-{}", i))
+        .build();
+    assert_eq!(pool.install(current_num_threads), 3);
+    let name = pool.install(|| std::thread::current().name().map(String::from));
+    assert!(name.unwrap().starts_with("adaptive-"));
+    // stock rayon can't send steal requests
+    let expected = if cfg!(feature = "steal-callback") {
+        Detection::StealRequests
+    } else {
+        Detection::Polling
+    };
+    assert_eq!(pool.install(polling::detection), expected);
+    assert!(pool.install(statistics::enabled));
+    // only the pool splits differently
+    assert_ne!(pool.install(task::multisplit), task::multisplit());
+    // our own pool gets the same options
+    let pool = AdaptivePoolBuilder::new()
+        .num_threads(2)
+        .builtin()
+        .statistics(true)
+        .thread_name(|i| format!("builtin-{}", i))
+        .build();
+    assert!(pool.install(|| pool::current_thread_index().is_some()));
+    let name = pool.install(|| std::thread::current().name().map(String::from));
+    assert!(name.unwrap().starts_with("builtin-"));
+    assert!(pool.install(statistics::enabled));
+
+    // nobody asks for work, so the task doesn't split
+    let pool = AdaptivePoolBuilder::new()
+        .num_threads(2)
+        .without_steal_requests()
+        .statistics(true)
+        .builtin()
+        .build();
+    let points = Point::create_random_points(2000);
+    pool.install(|| Searcher::new(&points).run());
+    let stats = statistics::of(&pool).total();
+    assert!(stats.steps > 0);
+    assert_eq!(
+        stats.splits + stats.steal_successes + stats.steal_failures,
+        0
+    );
+}
@@ -0,0 +1,53 @@
+//! Adaptive reductions over slices, so we don't have to write a new struct (like
+//! `points::Tester`) every time we want to fold something. The slice only gets divided when
+//! another thread asks for work. These are shortcuts for the adaptive iterators in `iter`.
+use crate::iter::{AdaptiveIterator, AdaptiveSlice};
+
+/// Fold `slice` adaptively: every piece starts from `identity()`, folds its elements in order
+/// with `fold` and neighbouring pieces get merged with `combine`.
+pub fn adaptive_reduce<T, R, ID, F, C>(slice: &[T], identity: ID, fold: F, combine: C) -> R
+where
+    T: Sync,
+    R: Send,
+    ID: Fn() -> R + Sync,
+    F: Fn(R, &T) -> R + Sync,
+    C: Fn(R, R) -> R + Sync,
+{
+    slice.adaptive_iter().fold(identity, fold, combine)
+}
+
+/// Call `op` on every element of `slice`, splitting only on steal requests.
+pub fn adaptive_for_each<T, F>(slice: &[T], op: F)
+where
+    T: Sync,
+    F: Fn(&T) + Sync,
+{
+    adaptive_reduce(slice, || (), |_, x| op(x), |_, _| ())
+}
+
+#[test]
+fn test_reduce() {
+    use crate::rayon::get_thread_pool;
+    let v: Vec<u64> = std::iter::repeat_with(|| rand::random::<u64>() % 10_000)
+        .take(100_000)
+        .collect();
+    let pool = get_thread_pool();
+    let sum = pool.install(|| adaptive_reduce(&v, || 0, |acc, x| acc + x, |a, b| a + b));
+    assert_eq!(sum, v.iter().sum::<u64>());
+    // combine isn't commutative here, so this also checks that pieces are fused in order
+    let copy = pool.install(|| {
+        adaptive_reduce(
+            &v,
+            Vec::new,
+            |mut acc, x| {
+                acc.push(*x);
+                acc
+            },
+            |mut a, mut b| {
+                a.append(&mut b);
+                a
+            },
+        )
+    });
+    assert_eq!(copy, v);
+}
@@ -0,0 +1,283 @@
+//! Adaptive prefix sums (scans). A thief just scans its own piece starting from its first
+//! element; when fusing we know the last value left of it and fix its piece up with that.
+//! If nobody steals we never do the fix-up, so a sequential run does n operations instead
+//! of the 2n of a two-pass scan.
+use crate::adaptive_bench::Benchable;
+use crate::task::Task;
+use crate::rayon_crate::prelude::*;
+use std::marker::PhantomData;
+use std::ops::{Add, Range};
+
+// how many elements we process before checking for steal requests again
+const BLOCK_SIZE: usize = 1024;
+
+struct Scanner<'a, T, O> {
+    // the whole slice we scan, every piece only touches its own ranges of it. Scanned ranges
+    // get fused back into one, so we can't just hold on to the subslices
+    data: *mut T,
+    marker: PhantomData<&'a mut [T]>,
+    // what we still have to scan
+    range: Range<usize>,
+    // everything we scanned already, our own blocks and the ones we got by fusing
+    done: Range<usize>,
+    // last value of our prefix, None as long as we didn't scan anything
+    last: Option<T>,
+    op: &'a O,
+}
+
+// Safety: it's just like a slice, and the ranges of the pieces don't overlap
+unsafe impl<'a, T: Send, O: Sync> Send for Scanner<'a, T, O> {}
+
+impl<'a, T, O> Scanner<'a, T, O> {
+    fn new(slice: &'a mut [T], op: &'a O) -> Self {
+        Scanner {
+            data: slice.as_mut_ptr(),
+            marker: PhantomData,
+            range: 0..slice.len(),
+            done: 0..0,
+            last: None,
+            op,
+        }
+    }
+    fn slice(&mut self, range: Range<usize>) -> &mut [T] {
+        // Safety: only ever called with our own ranges, which are all inside of the slice
+        unsafe { std::slice::from_raw_parts_mut(self.data.add(range.start), range.len()) }
+    }
+}
+
+impl<'a, T, O> Task for Scanner<'a, T, O>
+where
+    T: Clone + Send + Sync,
+    O: Fn(&T, &T) -> T + Sync,
+{
+    fn step(&mut self) {
+        let end = (self.range.start + BLOCK_SIZE).min(self.range.end);
+        let (op, mut last) = (self.op, self.last.take());
+        for x in self.slice(self.range.start..end).iter_mut() {
+            if let Some(last) = &last {
+                *x = op(last, x);
+            }
+            last = Some(x.clone());
+        }
+        self.last = last;
+        self.done.end = end;
+        self.range.start = end;
+    }
+    fn can_split(&self) -> bool {
+        self.range.len() > BLOCK_SIZE
+    }
+    fn split(&mut self, mut runner: impl FnMut(&mut Vec<&mut Self>), steal_counter: usize) {
+        // how many elements per task? We need at least one
+        let step = self.range.len() / (steal_counter + 1) + 1;
+        let end = self.range.end;
+        self.range.end = self.range.start + step;
+        let mut tasks = (self.range.end..end)
+            .step_by(step)
+            .map(|start| Scanner {
+                data: self.data,
+                marker: PhantomData,
+                range: start..(start + step).min(end),
+                done: start..start,
+                last: None,
+                op: self.op,
+            })
+            .collect::<Vec<_>>();
+        let mut tasks = tasks.iter_mut().collect::<Vec<&mut Self>>();
+        tasks.insert(0, self);
+        runner(&mut tasks);
+    }
+    fn is_finished(&self) -> bool {
+        self.range.is_empty()
+    }
+    fn fuse(&mut self, other: &mut Self) {
+        // other is right next to us, so everything it scanned is missing our last value
+        assert_eq!(self.done.end, other.done.start);
+        if let Some(last) = self.last.take() {
+            FixUp {
+                slice: other.slice(other.done.clone()),
+                offset: &last,
+                op: self.op,
+            }
+            .run();
+            self.last = match &other.last {
+                Some(other_last) => Some((self.op)(&last, other_last)),
+                None => Some(last),
+            };
+        } else {
+            self.last = other.last.take();
+        }
+        self.done.end = other.done.end;
+        other.done.start = other.done.end;
+    }
+}
+
+// Applies the prefix of everything left of a stolen piece, can be split itself
+struct FixUp<'a, T, O> {
+    slice: &'a mut [T],
+    offset: &'a T,
+    op: &'a O,
+}
+
+impl<'a, T, O> Task for FixUp<'a, T, O>
+where
+    T: Send + Sync,
+    O: Fn(&T, &T) -> T + Sync,
+{
+    fn step(&mut self) {
+        let size = BLOCK_SIZE.min(self.slice.len());
+        let (block, rest) = std::mem::take(&mut self.slice).split_at_mut(size);
+        for x in block.iter_mut() {
+            *x = (self.op)(self.offset, x);
+        }
+        self.slice = rest;
+    }
+    fn can_split(&self) -> bool {
+        self.slice.len() > BLOCK_SIZE
+    }
+    fn split(&mut self, mut runner: impl FnMut(&mut Vec<&mut Self>), steal_counter: usize) {
+        let step = self.slice.len() / (steal_counter + 1) + 1;
+        let (mine, mut rest) = std::mem::take(&mut self.slice).split_at_mut(step);
+        self.slice = mine;
+        let mut tasks = vec![];
+        while !rest.is_empty() {
+            let (piece, remaining) = rest.split_at_mut(step.min(rest.len()));
+            tasks.push(FixUp {
+                slice: piece,
+                offset: self.offset,
+                op: self.op,
+            });
+            rest = remaining;
+        }
+        let mut tasks = tasks.iter_mut().collect::<Vec<&mut Self>>();
+        tasks.insert(0, self);
+        runner(&mut tasks);
+    }
+    fn is_finished(&self) -> bool {
+        self.slice.is_empty()
+    }
+    fn fuse(&mut self, _other: &mut Self) {
+        // Nothing to do, all the work is done in place
+    }
+}
+
+/// Inclusive scan of `slice` in place, `op` needs to be associative (but not commutative).
+pub fn adaptive_scan<T, O>(slice: &mut [T], op: O)
+where
+    T: Clone + Send + Sync,
+    O: Fn(&T, &T) -> T + Sync,
+{
+    Scanner::new(slice, &op).run();
+}
+
+pub fn adaptive_prefix_sum<T>(slice: &mut [T])
+where
+    T: Copy + Add<Output = T> + Send + Sync,
+{
+    adaptive_scan(slice, |a, b| *a + *b)
+}
+
+/// The usual two-pass scan with rayon: scan one chunk per thread, then add the offsets.
+pub fn rayon_scan<T, O>(slice: &mut [T], op: O)
+where
+    T: Clone + Send + Sync,
+    O: Fn(&T, &T) -> T + Sync,
+{
+    if slice.is_empty() {
+        return;
+    }
+    let chunk_size = (slice.len() / crate::rayon_crate::current_num_threads()).max(1);
+    slice.par_chunks_mut(chunk_size).for_each(|chunk| {
+        for i in 1..chunk.len() {
+            chunk[i] = op(&chunk[i - 1], &chunk[i]);
+        }
+    });
+    // prefix of everything left of each chunk
+    let mut offsets = Vec::new();
+    let mut last: Option<T> = None;
+    for chunk in slice.chunks(chunk_size) {
+        offsets.push(last.clone());
+        let chunk_last = chunk.last().unwrap();
+        last = Some(match &last {
+            Some(last) => op(last, chunk_last),
+            None => chunk_last.clone(),
+        });
+    }
+    slice
+        .par_chunks_mut(chunk_size)
+        .zip(offsets)
+        .for_each(|(chunk, offset)| {
+            if let Some(offset) = offset {
+                chunk.iter_mut().for_each(|x| *x = op(&offset, x));
+            }
+        });
+}
+
+pub struct Scan {
+    input: Vec<u64>,
+    data: Vec<u64>,
+}
+impl Scan {
+    pub fn new(input: &[u64]) -> Self {
+        Scan {
+            input: input.to_vec(),
+            data: input.to_vec(),
+        }
+    }
+}
+impl<'a> Benchable<'a, u64> for Scan {
+    fn start(&mut self) -> Option<u64> {
+        self.data.copy_from_slice(&self.input);
+        adaptive_prefix_sum(&mut self.data);
+        self.data.last().cloned()
+    }
+    fn name(&self) -> &'static str {
+        "Adaptive"
+    }
+}
+
+pub struct RayonScan {
+    input: Vec<u64>,
+    data: Vec<u64>,
+}
+impl RayonScan {
+    pub fn new(input: &[u64]) -> Self {
+        RayonScan {
+            input: input.to_vec(),
+            data: input.to_vec(),
+        }
+    }
+}
+impl<'a> Benchable<'a, u64> for RayonScan {
+    fn start(&mut self) -> Option<u64> {
+        self.data.copy_from_slice(&self.input);
+        rayon_scan(&mut self.data, |a, b| a + b);
+        self.data.last().cloned()
+    }
+    fn name(&self) -> &'static str {
+        "Rayon"
+    }
+}
+
+#[test]
+fn test_scan() {
+    use crate::rayon::get_thread_pool;
+    let v: Vec<u64> = std::iter::repeat_with(|| rand::random::<u64>() % 1000)
+        .take(1_000_000)
+        .collect();
+    let mut expected = v.clone();
+    for i in 1..expected.len() {
+        expected[i] += expected[i - 1];
+    }
+    let pool = get_thread_pool();
+    let mut adaptive = v.clone();
+    pool.install(|| adaptive_prefix_sum(&mut adaptive));
+    assert_eq!(adaptive, expected);
+    let mut rayon = v.clone();
+    pool.install(|| rayon_scan(&mut rayon, |a, b| a + b));
+    assert_eq!(rayon, expected);
+    // this one answers steal requests, so we get stolen pieces to fix up
+    let pool = crate::rayon::get_builtin_thread_pool(4, 8);
+    let mut adaptive = v.clone();
+    pool.install(|| adaptive_prefix_sum(&mut adaptive));
+    assert_eq!(adaptive, expected);
+}
@@ -7,9 +7,11 @@ use std::sync::atomic::{AtomicU64, Ordering};
 
 // const P: usize = 2; // the number of processors we simulate
 
+use crate::policy::{default_policy, SplitPolicy};
 use crate::task::*;
-use rayon::prelude::*;
+use crate::rayon_crate::prelude::*;
 use std::ops::Range;
+use std::sync::Arc;
 // use crate::task::NOTHING;
 
 #[derive(Debug)]
@@ -19,7 +21,17 @@ pub struct Scheduling {
     pub best: u64,
     pub procs: Vec<u64>,
     pub decisions: Vec<Range<usize>>,
-    pub min_depth: usize
+    pub min_depth: usize,
+    // how many nodes a step should look at, as calibrated. min_depth follows it
+    pub step_size: usize,
+    // best makespan found by any piece so far, shared between all of them for pruning
+    pub incumbent: Arc<AtomicU64>,
+    // how many schedules (leaves of the decision tree) we cut off because they couldn't beat
+    // the incumbent, and how many we looked at. Together that's the whole tree
+    pub pruned: u64,
+    pub explored: u64,
+    // We split off one decision level per piece, so only the number of pieces matters here
+    pub policy: Arc<dyn SplitPolicy>,
 }
 impl Scheduling {
     pub fn new(remaining_times: &Vec<u64>, procs: &Vec<u64>,min_depth: usize) -> Self {
@@ -29,7 +41,12 @@ impl Scheduling {
             best: std::u64::MAX,
             procs: procs.clone(),
             decisions: Vec::new(),
-            min_depth
+            min_depth,
+            step_size: procs.len().saturating_pow(min_depth as u32),
+            incumbent: Arc::new(AtomicU64::new(std::u64::MAX)),
+            pruned: 0,
+            explored: 0,
+            policy: default_policy(),
         };
         // Do the first step, else isFinished reports it's finished without doing anything :O
         s.decisions.push(Range {
@@ -39,6 +56,10 @@ impl Scheduling {
         s.procs[0] += s.remaining_times[0];
         s
     }
+    pub fn with_policy(mut self, policy: Arc<dyn SplitPolicy>) -> Self {
+        self.policy = policy;
+        self
+    }
     fn _debug(&mut self) {
         println!("-----------");
         println!("Times     : {:?}", self.remaining_times);
@@ -82,24 +103,61 @@ impl Scheduling {
 }
 impl Task for Scheduling {
     fn step(&mut self) {
-        // self.print();
-        // println!("Depth: {}, decisions: {:?}", self.index, self.decisions);
-        // Sequential cut-off
-        if self.remaining_times.len() - self.decisions.len() <= self.min_depth {
-            // subgraph("Cut-off", 1, || {
-            self.best = self.best.min(brute_force_rec(
-                &mut self.procs,
-                &mut self.remaining_times[self.decisions.len()..],
-            ));
-            // });
-            self.next();
+        // We go down the tree until we did one sequential cut-off, so a step is about
+        // procs^min_depth nodes and the step size can be calibrated with min_depth
+        while !self.is_finished() {
+            // self.print();
+            // println!("Depth: {}, decisions: {:?}", self.index, self.decisions);
+            let incumbent = self.incumbent.load(Ordering::Relaxed);
+            // Adding more tasks won't make it better, no need to look at this subtree
+            if self.procs.iter().max().cloned().unwrap() >= incumbent {
+                let levels = self.remaining_times.len() - self.decisions.len();
+                self.pruned += (self.procs.len() as u64).saturating_pow(levels as u32);
+                self.next();
+                continue;
+            }
+            // Sequential cut-off
+            if self.remaining_times.len() - self.decisions.len() <= self.min_depth {
+                // subgraph("Cut-off", 1, || {
+                let best = branch_and_bound_rec(
+                    &mut self.procs,
+                    &self.remaining_times[self.decisions.len()..],
+                    incumbent,
+                    &mut self.pruned,
+                    &mut self.explored,
+                );
+                self.best = self.best.min(best);
+                self.incumbent.fetch_min(best, Ordering::Relaxed);
+                // });
+                self.next();
+                return;
+            }
+            self.decisions.push(Range {
+                start: 0,
+                end: self.procs.len(),
+            });
+            self.procs[0] += self.remaining_times[self.decisions.len() - 1];
+        }
+    }
+    fn step_size(&self) -> Option<usize> {
+        Some(self.step_size)
+    }
+    fn set_step_size(&mut self, size: usize) {
+        self.step_size = size.max(1);
+        let p = self.procs.len();
+        if p < 2 {
             return;
         }
-        self.decisions.push(Range {
-            start: 0,
-            end: self.procs.len(),
-        });
-        self.procs[0] += self.remaining_times[self.decisions.len() - 1];
+        // A level is p times the work, so we only change it once the size is a whole level
+        // away. Otherwise we'd keep flipping between two levels
+        let (floor, exact) = log(self.step_size, p);
+        let ceil = if exact { floor } else { floor + 1 };
+        if floor > self.min_depth {
+            self.min_depth = floor;
+        } else if ceil < self.min_depth {
+            self.min_depth = ceil;
+        }
+        self.min_depth = self.min_depth.min(self.remaining_times.len());
     }
     fn can_split(&self) -> bool {
         // We need a tree that has a choice left (meaning 2 branches, one that is currently
@@ -110,6 +168,7 @@ impl Task for Scheduling {
     fn split(&mut self, mut runner: impl FnMut(&mut Vec<&mut Self>), steal_counter: usize) {
         let mut splits = Vec::new();
         let mut split = 0;
+        let pieces = self.policy.pieces(steal_counter);
         for i in 0..self.decisions.len() {
             if self.decisions[i].end - self.decisions[i].start >= 2 {
                 let mut other = Scheduling {
@@ -117,7 +176,12 @@ impl Task for Scheduling {
                     best: self.best,
                     procs: self.procs.clone(),
                     decisions: self.decisions.clone(),
-                    min_depth : self.min_depth
+                    min_depth: self.min_depth,
+                    step_size: self.step_size,
+                    incumbent: self.incumbent.clone(),
+                    pruned: 0,
+                    explored: 0,
+                    policy: self.policy.clone(),
                 };
 
                 let other_range = Scheduling::split_range(&mut self.decisions[i]);
@@ -127,7 +191,7 @@ impl Task for Scheduling {
                 other.redo_tree();
                 splits.push(other);
                 split += 1;
-                if split == steal_counter {
+                if split + 1 >= pieces {
                     break;
                 }
             }
@@ -143,6 +207,8 @@ impl Task for Scheduling {
     }
     fn fuse(&mut self, other: &mut Self) {
         self.best = other.best.min(self.best);
+        self.pruned += other.pruned;
+        self.explored += other.explored;
     }
     fn is_finished(&self) -> bool {
         self.decisions.is_empty()
@@ -167,25 +233,78 @@ fn test_scheduling() {
     let my_result = s.start();
     let pool = get_thread_pool();
     let mut s = Scheduling::new(&times, &procs, 5);
-    pool.install(|| s.start());
-    // s.start();
+    let pool_result = pool.install(|| s.start());
     let mut b = BruteForcePar::new(times.clone(), procs.clone());
     let other_result = b.start();
     assert_eq!(my_result, other_result);
-    // s.verify(&b.get_result());
+    assert_eq!(pool_result, other_result);
+}
+
+#[test]
+fn test_pruning() {
+    let times: Vec<u64> = std::iter::repeat_with(|| rand::random::<u64>() % 10_000)
+        .take(12)
+        .collect();
+    let procs = vec![0; 3];
+    let expected = BruteForce::new(times.clone(), procs.clone()).start();
+    // this pool answers steal requests, so the pieces have to share the incumbent
+    let pool = crate::rayon::get_builtin_thread_pool(4, 8);
+    let mut s = Scheduling::new(&times, &procs, 4);
+    assert_eq!(pool.install(|| s.start()), expected);
+    assert!(s.pruned > 0);
+    // every schedule is either pruned or looked at, and only once
+    assert_eq!(s.pruned + s.explored, 3u64.pow(12));
+}
+
+// floor(log_p(size)) and if it's exact, without any float rounding
+fn log(size: usize, p: usize) -> (usize, bool) {
+    let (mut log, mut power) = (0, 1usize);
+    while let Some(next) = power.checked_mul(p).filter(|&next| next <= size) {
+        log += 1;
+        power = next;
+    }
+    (log, power == size)
+}
+
+#[test]
+fn test_step_size() {
+    let times = vec![1; 12];
+    let procs = vec![0; 5];
+    let mut s = Scheduling::new(&times, &procs, 2);
+    assert_eq!(s.step_size(), Some(25));
+    // double it until it's a level deeper, that takes a few steps with 5 processors
+    for &depth in &[2, 2, 3] {
+        let size = s.step_size().unwrap();
+        s.set_step_size(size * 2);
+        assert_eq!(s.min_depth, depth);
+    }
+    assert_eq!(s.step_size(), Some(200));
+    // and it takes a whole level to go back up
+    for &depth in &[3, 3, 2, 2] {
+        let size = s.step_size().unwrap();
+        s.set_step_size(size / 2);
+        assert_eq!(s.min_depth, depth);
+    }
+    assert_eq!(log(125, 5), (3, true));
+    assert_eq!(log(124, 5), (2, false));
+    assert_eq!(log(std::usize::MAX, 2), (63, false));
 }
 
 use crate::adaptive_bench::Benchable;
 impl<'a> Benchable<'a, u64> for Scheduling {
     fn start(&mut self) -> Option<u64> {
         self.procs.iter_mut().for_each(|p| *p = 0);
-        *self = Self::new(&self.remaining_times, &self.procs, self.min_depth);
+        *self = Self::new(&self.remaining_times, &self.procs, self.min_depth)
+            .with_policy(self.policy.clone());
         self.run();
         Some(self.best)
     }
     fn name(&self) -> &'static str {
         "Adaptive"
     }
+    fn summary(&self) -> Option<String> {
+        Some(format!("Pruned schedules: {}", self.pruned))
+    }
 }
 
 pub struct BruteForcePar {
@@ -277,13 +396,13 @@ fn brute_force_par(times: &[u64], mut procs: Vec<u64>) -> u64 {
         // Really only works for two processors...
         return brute_force_rec_par_split(procs, times);
     }
-    let levels = (rayon::current_num_threads() as f64).log2().ceil() * 2.0;
+    let levels = (crate::rayon_crate::current_num_threads() as f64).log2().ceil() * 2.0;
     // let mut procs: Vec<u64> = std::iter::repeat(0).take(2).collect();
     brute_force_rec_par(&mut procs, times, levels as usize)
 }
 
 fn brute_force_rec_par_split(procs: Vec<u64>, times: &[u64]) -> u64 {
-    rayon::iter::split((procs, times), |(mut procs, times)| {
+    crate::rayon_crate::iter::split((procs, times), |(mut procs, times)| {
         if let Some((first, rest)) = times.split_first() {
             // let times2 = rest.clone();
             let mut procs2 = procs.clone();
@@ -326,11 +445,19 @@ fn brute_force_rec_par(procs: &mut Vec<u64>, times: &[u64], levels: usize) -> u6
 
 pub fn branch_and_bound(times: &[u64], initial_solution: u64) -> u64 {
     let mut procs: Vec<u64> = std::iter::repeat(0).take(3).collect();
-    branch_and_bound_rec(&mut procs, times, initial_solution)
+    branch_and_bound_rec(&mut procs, times, initial_solution, &mut 0, &mut 0)
 }
 
-pub fn branch_and_bound_rec(procs: &mut Vec<u64>, times: &[u64], mut best_solution: u64) -> u64 {
+/// Counts the schedules it prunes and the ones it looks at in `pruned` and `explored`.
+pub fn branch_and_bound_rec(
+    procs: &mut Vec<u64>,
+    times: &[u64],
+    mut best_solution: u64,
+    pruned: &mut u64,
+    explored: &mut u64,
+) -> u64 {
     if procs.iter().max().cloned().unwrap() >= best_solution {
+        *pruned += (procs.len() as u64).saturating_pow(times.len() as u32);
         best_solution
     } else {
         times
@@ -338,7 +465,13 @@ pub fn branch_and_bound_rec(procs: &mut Vec<u64>, times: &[u64], mut best_soluti
             .map(|(time, remaining_times)| {
                 for i in 0..procs.len() {
                     procs[i] += time;
-                    let r = branch_and_bound_rec(procs, remaining_times, best_solution);
+                    let r = branch_and_bound_rec(
+                        procs,
+                        remaining_times,
+                        best_solution,
+                        pruned,
+                        explored,
+                    );
                     if r < best_solution {
                         best_solution = r
                     }
@@ -346,7 +479,10 @@ pub fn branch_and_bound_rec(procs: &mut Vec<u64>, times: &[u64], mut best_soluti
                 }
                 best_solution
             })
-            .unwrap_or_else(|| procs.iter().max().cloned().unwrap())
+            .unwrap_or_else(|| {
+                *explored += 1;
+                procs.iter().max().cloned().unwrap()
+            })
     }
 }
 
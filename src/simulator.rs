@@ -0,0 +1,365 @@
+//! Runs a `Task` on virtual workers in a discrete-event simulation, to look at split policies
+//! and steal strategies without the noise of a real machine. A step takes as long as the cost
+//! model says, steal requests (and the pieces we answer them with) take `steal_latency` to get
+//! to the other worker, and thieves give up on a victim after `steal_wait`. Everything random
+//! comes from one seeded rng, so the same seed always gives the same run.
+//! The pieces of a split only live as long as the runner we give to `Task::split`, so we
+//! simulate on inside the runner until all its pieces are done and then fuse them, just like
+//! the real runner. Splits of other workers in the meantime go one runner deeper. If an outer
+//! split is done before that, we only notice once we're back in its runner, and count it as
+//! done when its last piece was.
+//! Step sizes don't get calibrated, and tasks nested in a step just run as part of it.
+use crate::task::{multisplit, Task};
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use std::collections::VecDeque;
+use std::fmt;
+
+#[derive(Debug, Clone)]
+pub struct Simulator {
+    num_workers: usize,
+    steal_latency: u64,
+    steal_wait: u64,
+    multisplit: bool,
+    seed: u64,
+}
+
+impl Simulator {
+    pub fn new(num_workers: usize) -> Self {
+        assert!(num_workers > 0);
+        Simulator {
+            num_workers,
+            steal_latency: 10,
+            steal_wait: 100,
+            multisplit: multisplit(),
+            seed: 0,
+        }
+    }
+    /// How long it takes a steal request or a stolen piece to get to the other worker.
+    pub fn steal_latency(mut self, latency: u64) -> Self {
+        self.steal_latency = latency;
+        self
+    }
+    /// How long thieves wait for an answer before they try another victim.
+    pub fn steal_wait(mut self, wait: u64) -> Self {
+        self.steal_wait = wait;
+        self
+    }
+    /// Split into one piece per thief or just in two, defaults to what `task::multisplit` says.
+    pub fn multisplit(mut self, multisplit: bool) -> Self {
+        self.multisplit = multisplit;
+        self
+    }
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+    /// Run `task` to the end, `cost` says how long its next step takes (at least 1).
+    pub fn run<T: Task>(&self, task: &mut T, cost: impl FnMut(&T) -> u64) -> Report {
+        let mut run = Run {
+            config: self,
+            rng: StdRng::seed_from_u64(self.seed),
+            cost,
+            workers: (0..self.num_workers)
+                .map(|_| Worker {
+                    clock: 0,
+                    state: State::Idle,
+                    deque: VecDeque::new(),
+                })
+                .collect(),
+            jobs: Vec::new(),
+            frames: vec![Frame {
+                remaining: 1,
+                finished_at: 0,
+                last_worker: 0,
+            }],
+            report: Report::default(),
+        };
+        run.jobs.push(Job {
+            task: task as *mut T,
+            frame: 0,
+        });
+        run.workers[0].state = State::Running(0);
+        run.run_until(0);
+        let mut report = run.report;
+        report.makespan = run.frames[0].finished_at;
+        report.idle_time = report.makespan * self.num_workers as u64 - report.work;
+        report
+    }
+}
+
+/// What happened in a simulated run, all times in the unit of the cost model.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    /// when the task was done
+    pub makespan: u64,
+    /// sum of the costs of all steps
+    pub work: u64,
+    /// time the workers spent looking for work until the end
+    pub idle_time: u64,
+    pub splits: usize,
+    pub steps: usize,
+    pub steal_requests: usize,
+    /// steal requests nobody answered in time
+    pub failed_steal_requests: usize,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "makespan {} ({} work, {} idle), {} splits in {} steps, {} of {} steal requests failed",
+            self.makespan,
+            self.work,
+            self.idle_time,
+            self.splits,
+            self.steps,
+            self.failed_steal_requests,
+            self.steal_requests
+        )
+    }
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Idle,
+    Running(usize),
+    // our clock is when we give up
+    Waiting { victim: usize, arrival: u64 },
+}
+
+struct Worker {
+    // when the next thing happens on this worker
+    clock: u64,
+    state: State,
+    // pieces nobody asked for yet
+    deque: VecDeque<usize>,
+}
+
+struct Job<T> {
+    // lives in the runner of the split it came from (or is the task we run)
+    task: *mut T,
+    frame: usize,
+}
+
+// The pieces of one split
+struct Frame {
+    remaining: usize,
+    finished_at: u64,
+    last_worker: usize,
+}
+
+struct Run<'s, T, C> {
+    config: &'s Simulator,
+    rng: StdRng,
+    cost: C,
+    workers: Vec<Worker>,
+    jobs: Vec<Job<T>>,
+    frames: Vec<Frame>,
+    report: Report,
+}
+
+impl<'s, T, C> Run<'s, T, C>
+where
+    T: Task,
+    C: FnMut(&T) -> u64,
+{
+    fn random(&mut self, n: usize) -> usize {
+        (self.rng.next_u64() % n as u64) as usize
+    }
+    // the worker with the earliest clock goes next, the lowest index on ties
+    fn run_until(&mut self, frame: usize) {
+        while self.frames[frame].remaining > 0 {
+            let worker = (0..self.workers.len())
+                .min_by_key(|&w| self.workers[w].clock)
+                .unwrap();
+            match self.workers[worker].state {
+                State::Idle => self.find_work(worker),
+                State::Running(job) => self.advance(worker, job),
+                State::Waiting { .. } => {
+                    // nobody answered
+                    self.report.failed_steal_requests += 1;
+                    self.workers[worker].state = State::Idle;
+                }
+            }
+        }
+    }
+    fn find_work(&mut self, worker: usize) {
+        if let Some(job) = self.workers[worker].deque.pop_back() {
+            self.workers[worker].state = State::Running(job);
+            return;
+        }
+        let n = self.workers.len();
+        let start = self.random(n);
+        for i in 0..n {
+            let victim = (start + i) % n;
+            if victim == worker {
+                continue;
+            }
+            if let Some(job) = self.workers[victim].deque.pop_front() {
+                self.workers[worker].state = State::Running(job);
+                self.workers[worker].clock += self.config.steal_latency;
+                return;
+            }
+        }
+        let running = self
+            .workers
+            .iter()
+            .any(|w| matches!(w.state, State::Running(_)));
+        assert!(running, "nothing left to run, but the task isn't done");
+        let victim = (worker + 1 + self.random(n - 1)) % n;
+        let clock = self.workers[worker].clock;
+        self.report.steal_requests += 1;
+        self.workers[worker].state = State::Waiting {
+            victim,
+            arrival: clock + self.config.steal_latency,
+        };
+        self.workers[worker].clock = clock + self.config.steal_wait.max(1);
+    }
+    fn advance(&mut self, worker: usize, job: usize) {
+        let task = unsafe { &mut *self.jobs[job].task };
+        let clock = self.workers[worker].clock;
+        if task.is_finished() || task.is_cancelled() {
+            self.workers[worker].state = State::Idle;
+            self.complete(job, clock, worker);
+            return;
+        }
+        let thieves = self.thieves(worker);
+        if !thieves.is_empty() && task.can_split() {
+            let count = thieves.len().min(self.workers.len() - 1);
+            let count = if self.config.multisplit { count } else { 1 };
+            self.split(worker, job, thieves, count);
+            return;
+        }
+        let cost = (self.cost)(task).max(1);
+        task.step_nested(&mut ());
+        self.workers[worker].clock += cost;
+        self.report.work += cost;
+        self.report.steps += 1;
+    }
+    // the requests that got to us by now, first come first served
+    fn thieves(&self, victim: usize) -> Vec<usize> {
+        let clock = self.workers[victim].clock;
+        let mut thieves = self
+            .workers
+            .iter()
+            .enumerate()
+            .filter_map(|(thief, w)| match w.state {
+                State::Waiting { victim: v, arrival } if v == victim && arrival <= clock => {
+                    Some((arrival, thief))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        thieves.sort();
+        thieves.into_iter().map(|(_, thief)| thief).collect()
+    }
+    fn split(&mut self, worker: usize, job: usize, thieves: Vec<usize>, count: usize) {
+        self.report.splits += 1;
+        let task = unsafe { &mut *self.jobs[job].task };
+        let clock = self.workers[worker].clock;
+        let mut frame = None;
+        task.split(
+            |pieces| {
+                let f = self.frames.len();
+                frame = Some(f);
+                self.frames.push(Frame {
+                    remaining: pieces.len(),
+                    finished_at: clock,
+                    last_worker: worker,
+                });
+                let jobs = pieces
+                    .iter_mut()
+                    .map(|piece| {
+                        self.jobs.push(Job {
+                            task: &mut **piece as *mut T,
+                            frame: f,
+                        });
+                        self.jobs.len() - 1
+                    })
+                    .collect::<Vec<_>>();
+                // we go on with the first piece, the thieves get the next ones and the rest
+                // can be stolen
+                self.workers[worker].state = State::Running(jobs[0]);
+                let mut rest = jobs[1..].iter();
+                for (&thief, &job) in thieves.iter().zip(&mut rest) {
+                    self.workers[thief].state = State::Running(job);
+                    self.workers[thief].clock = clock + self.config.steal_latency;
+                }
+                self.workers[worker].deque.extend(rest);
+                self.run_until(f);
+                // fuse from the right, like Task::runner
+                while pieces.len() > 1 {
+                    let last = pieces.pop().unwrap();
+                    pieces.last_mut().unwrap().fuse(last);
+                }
+            },
+            count,
+        );
+        if let Some(frame) = frame {
+            self.resume(job, frame);
+        }
+    }
+    // The task we split is fused, it's usually done now. If not, whoever finished the last
+    // piece can go on with it
+    fn resume(&mut self, job: usize, frame: usize) {
+        let finished_at = self.frames[frame].finished_at;
+        let last_worker = self.frames[frame].last_worker;
+        let task = unsafe { &*self.jobs[job].task };
+        if task.is_finished() || task.is_cancelled() {
+            self.complete(job, finished_at, last_worker);
+        } else {
+            self.workers[last_worker].deque.push_back(job);
+        }
+    }
+    fn complete(&mut self, job: usize, time: u64, worker: usize) {
+        let frame = &mut self.frames[self.jobs[job].frame];
+        frame.remaining -= 1;
+        if time >= frame.finished_at {
+            frame.finished_at = time;
+            frame.last_worker = worker;
+        }
+    }
+}
+
+#[test]
+fn test_simulator() {
+    use crate::points::{Point, Searcher};
+    use crate::scheduling::Scheduling;
+    let points = Point::create_random_points(500);
+    let mut s = Searcher::new(&points);
+    s.run();
+    let expected = s.min();
+
+    let simulator = Simulator::new(4).steal_latency(5).steal_wait(50).seed(42);
+    let mut s = Searcher::new(&points);
+    let report = simulator.run(&mut s, |_| 100);
+    assert_eq!(s.min(), expected);
+    assert_eq!(report.steps, points.len());
+    assert_eq!(report.work, 100 * points.len() as u64);
+    assert!(report.splits > 0);
+    assert!(report.makespan < report.work);
+    assert_eq!(report.idle_time, 4 * report.makespan - report.work);
+    // same seed, same run
+    let mut s = Searcher::new(&points);
+    assert_eq!(simulator.run(&mut s, |_| 100), report);
+    // nobody to steal
+    let mut s = Searcher::new(&points);
+    let alone = Simulator::new(1).run(&mut s, |_| 100);
+    assert_eq!((alone.makespan, alone.splits), (report.work, 0));
+
+    let times: Vec<u64> = std::iter::repeat_with(|| rand::random::<u64>() % 10_000)
+        .take(10)
+        .collect();
+    let procs = vec![0; 3];
+    let mut scheduling = Scheduling::new(&times, &procs, 4);
+    scheduling.run();
+    let best = scheduling.best;
+    let cost = |s: &Scheduling| s.step_size().unwrap() as u64;
+    let simulator = Simulator::new(8).multisplit(true).seed(7);
+    let mut scheduling = Scheduling::new(&times, &procs, 4);
+    let report = simulator.run(&mut scheduling, cost);
+    assert_eq!(scheduling.best, best);
+    let mut scheduling = Scheduling::new(&times, &procs, 4);
+    assert_eq!(simulator.run(&mut scheduling, cost), report);
+}
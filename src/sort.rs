@@ -0,0 +1,245 @@
+//! Adaptive stable merge sort. Every piece sorts blocks of its part and merges them like a
+//! binary counter, so without steals this is a plain sequential merge sort. Stolen pieces are
+//! merged back when fusing, and that merge can be split again.
+use crate::adaptive_bench::Benchable;
+use crate::merge::adaptive_merge;
+use crate::task::Task;
+use crate::rayon_crate::prelude::*;
+use std::marker::PhantomData;
+use std::ops::Range;
+
+// how many elements we sort in one step
+const BLOCK_SIZE: usize = 1024;
+
+// The whole slice we sort and the scratch space for merging (same size). Pieces get a range of
+// it and neighbours are fused back into one range, so we keep pointers to all of it: subslices
+// couldn't be glued back together.
+struct Root<'a, T> {
+    data: *mut T,
+    buffer: *mut T,
+    marker: PhantomData<&'a mut [T]>,
+}
+impl<'a, T> Clone for Root<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<'a, T> Copy for Root<'a, T> {}
+// Safety: it's just like the slices, every piece only touches its own range
+unsafe impl<'a, T: Send> Send for Root<'a, T> {}
+
+struct Sorter<'a, T> {
+    root: Root<'a, T>,
+    // our part of the root, we're the only ones touching it
+    range: Range<usize>,
+    // data[..sorted] consists of sorted runs
+    sorted: usize,
+    // lengths of these runs, from left to right
+    runs: Vec<usize>,
+}
+
+impl<'a, T> Sorter<'a, T>
+where
+    T: Ord + Clone + Send + Sync,
+{
+    fn new(data: &'a mut [T], buffer: &'a mut [T]) -> Self {
+        assert_eq!(data.len(), buffer.len());
+        Sorter {
+            root: Root {
+                data: data.as_mut_ptr(),
+                buffer: buffer.as_mut_ptr(),
+                marker: PhantomData,
+            },
+            range: 0..data.len(),
+            sorted: 0,
+            runs: Vec::new(),
+        }
+    }
+    fn len(&self) -> usize {
+        self.range.len()
+    }
+    // our part of the data and of the buffer
+    fn parts(&mut self) -> (&mut [T], &mut [T]) {
+        let (start, len) = (self.range.start, self.len());
+        // Safety: the ranges of the pieces don't overlap and they're all inside of the root
+        unsafe {
+            (
+                std::slice::from_raw_parts_mut(self.root.data.add(start), len),
+                std::slice::from_raw_parts_mut(self.root.buffer.add(start), len),
+            )
+        }
+    }
+    fn merge_last_runs(&mut self) {
+        let right = self.runs.pop().unwrap();
+        let left = self.runs.pop().unwrap();
+        let start = self.sorted - left - right;
+        let end = self.sorted;
+        let (data, buffer) = self.parts();
+        let buffer = &mut buffer[start..end];
+        buffer.clone_from_slice(&data[start..end]);
+        let (a, b) = buffer.split_at(left);
+        adaptive_merge(a, b, &mut data[start..end]);
+        self.runs.push(left + right);
+    }
+}
+
+impl<'a, T> Task for Sorter<'a, T>
+where
+    T: Ord + Clone + Send + Sync,
+{
+    fn step(&mut self) {
+        if self.sorted == self.len() {
+            // everything is in runs, just merge what's left
+            self.merge_last_runs();
+            return;
+        }
+        let (sorted, end) = (self.sorted, (self.sorted + BLOCK_SIZE).min(self.len()));
+        self.parts().0[sorted..end].sort();
+        self.runs.push(end - sorted);
+        self.sorted = end;
+        // merge runs of the same size, so every element gets merged O(log n) times
+        while self.runs.len() >= 2
+            && self.runs[self.runs.len() - 1] >= self.runs[self.runs.len() - 2]
+        {
+            self.merge_last_runs();
+        }
+    }
+    fn can_split(&self) -> bool {
+        self.len() - self.sorted > BLOCK_SIZE
+    }
+    fn split(&mut self, mut runner: impl FnMut(&mut Vec<&mut Self>), steal_counter: usize) {
+        // we only give away the part we didn't start sorting yet
+        let step = (self.len() - self.sorted) / (steal_counter + 1) + 1;
+        let end = self.range.end;
+        self.range.end = self.range.start + self.sorted + step;
+        let root = self.root;
+        let mut tasks = (self.range.end..end)
+            .step_by(step)
+            .map(|start| Sorter {
+                root,
+                range: start..(start + step).min(end),
+                sorted: 0,
+                runs: Vec::new(),
+            })
+            .collect::<Vec<_>>();
+        let mut tasks = tasks.iter_mut().collect::<Vec<&mut Self>>();
+        tasks.insert(0, self);
+        runner(&mut tasks);
+    }
+    fn is_finished(&self) -> bool {
+        self.sorted == self.len() && self.runs.len() <= 1
+    }
+    fn fuse(&mut self, other: &mut Self) {
+        // both of us are sorted and other is right next to us, its range is ours now
+        assert_eq!(self.range.end, other.range.start);
+        let left = self.len();
+        self.range.end = other.range.end;
+        other.range.start = other.range.end;
+        let (data, buffer) = self.parts();
+        buffer.clone_from_slice(data);
+        let (a, b) = buffer.split_at(left);
+        adaptive_merge(a, b, data);
+        self.sorted = self.len();
+        self.runs = vec![self.sorted];
+    }
+}
+
+/// Stable sort, only split when somebody wants to steal.
+pub fn adaptive_sort<T>(slice: &mut [T])
+where
+    T: Ord + Clone + Send + Sync,
+{
+    let mut buffer = slice.to_vec();
+    Sorter::new(slice, &mut buffer).run();
+}
+
+pub struct Sort {
+    input: Vec<u64>,
+    data: Vec<u64>,
+}
+impl Sort {
+    pub fn new(input: &[u64]) -> Self {
+        Sort {
+            input: input.to_vec(),
+            data: input.to_vec(),
+        }
+    }
+}
+impl<'a> Benchable<'a, u64> for Sort {
+    fn start(&mut self) -> Option<u64> {
+        self.data.copy_from_slice(&self.input);
+        adaptive_sort(&mut self.data);
+        self.data.get(self.data.len() / 2).cloned()
+    }
+    fn name(&self) -> &'static str {
+        "Adaptive"
+    }
+}
+
+pub struct RayonSort {
+    input: Vec<u64>,
+    data: Vec<u64>,
+}
+impl RayonSort {
+    pub fn new(input: &[u64]) -> Self {
+        RayonSort {
+            input: input.to_vec(),
+            data: input.to_vec(),
+        }
+    }
+}
+impl<'a> Benchable<'a, u64> for RayonSort {
+    fn start(&mut self) -> Option<u64> {
+        self.data.copy_from_slice(&self.input);
+        self.data.par_sort();
+        self.data.get(self.data.len() / 2).cloned()
+    }
+    fn name(&self) -> &'static str {
+        "Rayon"
+    }
+}
+
+#[test]
+fn test_sort() {
+    use crate::rayon::get_thread_pool;
+    // only compares the key, so we can see if equal elements stay in order
+    #[derive(Clone, Debug)]
+    struct Item {
+        key: u64,
+        index: usize,
+    }
+    impl PartialEq for Item {
+        fn eq(&self, other: &Self) -> bool {
+            self.key == other.key
+        }
+    }
+    impl Eq for Item {}
+    impl PartialOrd for Item {
+        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+    impl Ord for Item {
+        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+            self.key.cmp(&other.key)
+        }
+    }
+    let v: Vec<Item> = (0..1_000_000)
+        .map(|index| Item {
+            key: rand::random::<u64>() % 1000,
+            index,
+        })
+        .collect();
+    let mut expected = v.clone();
+    expected.sort_by_key(|item| item.key);
+    let pool = get_thread_pool();
+    let indices = |v: &[Item]| v.iter().map(|item| item.index).collect::<Vec<usize>>();
+    let mut adaptive = v.clone();
+    pool.install(|| adaptive_sort(&mut adaptive));
+    assert_eq!(indices(&adaptive), indices(&expected));
+    // this one answers steal requests, so pieces get split and fused back together
+    let pool = crate::rayon::get_builtin_thread_pool(4, 8);
+    let mut adaptive = v.clone();
+    pool.install(|| adaptive_sort(&mut adaptive));
+    assert_eq!(indices(&adaptive), indices(&expected));
+}
@@ -0,0 +1,538 @@
+//! Statistics about steals and steps, counted per worker thread. Every pool we build in
+//! `crate::rayon` gets its own registry of counters (set up in the start handler), so pools
+//! can be measured and reset on their own. Threads that aren't in one of our pools (and the
+//! rayon_logs pools) count into a global registry.
+//! Collecting is switched on and off at runtime with `set_enabled` (or for a single pool with
+//! `AdaptivePoolBuilder::statistics`), the `statistics` feature only turns it on by default.
+//! Timing every step would slow down small steps quite a bit, so we only time every
+//! `sample_rate`-th step.
+use crate::rayon::AdaptivePool;
+use crossbeam_utils::CachePadded;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// steal counters up to this go into their own bucket, bigger ones into the last one
+const HISTOGRAM_SIZE: usize = 32;
+
+struct Counters {
+    splits: AtomicUsize,
+    steal_counter: AtomicUsize,
+    histogram: Vec<AtomicUsize>,
+    steal_successes: AtomicUsize,
+    steal_failures: AtomicUsize,
+    remote_steal_successes: AtomicUsize,
+    remote_steal_failures: AtomicUsize,
+    neighbour_steal_successes: AtomicUsize,
+    neighbour_steal_failures: AtomicUsize,
+    steps: AtomicUsize,
+    timed_steps: AtomicUsize,
+    step_time: AtomicUsize,
+    // what StealStrategy::Adaptive tuned itself to, in nanoseconds
+    steal_wait: AtomicUsize,
+    steal_latency: AtomicUsize,
+}
+
+impl Counters {
+    fn new() -> Self {
+        Counters {
+            splits: AtomicUsize::new(0),
+            steal_counter: AtomicUsize::new(0),
+            histogram: (0..HISTOGRAM_SIZE).map(|_| AtomicUsize::new(0)).collect(),
+            steal_successes: AtomicUsize::new(0),
+            steal_failures: AtomicUsize::new(0),
+            remote_steal_successes: AtomicUsize::new(0),
+            remote_steal_failures: AtomicUsize::new(0),
+            neighbour_steal_successes: AtomicUsize::new(0),
+            neighbour_steal_failures: AtomicUsize::new(0),
+            steps: AtomicUsize::new(0),
+            timed_steps: AtomicUsize::new(0),
+            step_time: AtomicUsize::new(0),
+            steal_wait: AtomicUsize::new(0),
+            steal_latency: AtomicUsize::new(0),
+        }
+    }
+    fn snapshot(&self) -> ThreadStatistics {
+        ThreadStatistics {
+            splits: self.splits.load(Relaxed),
+            steal_counter: self.steal_counter.load(Relaxed),
+            steal_counter_histogram: self.histogram.iter().map(|h| h.load(Relaxed)).collect(),
+            steal_successes: self.steal_successes.load(Relaxed),
+            steal_failures: self.steal_failures.load(Relaxed),
+            remote_steal_successes: self.remote_steal_successes.load(Relaxed),
+            remote_steal_failures: self.remote_steal_failures.load(Relaxed),
+            neighbour_steal_successes: self.neighbour_steal_successes.load(Relaxed),
+            neighbour_steal_failures: self.neighbour_steal_failures.load(Relaxed),
+            steps: self.steps.load(Relaxed),
+            timed_steps: self.timed_steps.load(Relaxed),
+            step_time: Duration::from_nanos(self.step_time.load(Relaxed) as u64),
+            steal_wait: Duration::from_nanos(self.steal_wait.load(Relaxed) as u64),
+            steal_latency: Duration::from_nanos(self.steal_latency.load(Relaxed) as u64),
+        }
+    }
+    fn reset(&self) {
+        self.splits.store(0, Relaxed);
+        self.steal_counter.store(0, Relaxed);
+        self.histogram.iter().for_each(|h| h.store(0, Relaxed));
+        self.steal_successes.store(0, Relaxed);
+        self.steal_failures.store(0, Relaxed);
+        self.remote_steal_successes.store(0, Relaxed);
+        self.remote_steal_failures.store(0, Relaxed);
+        self.neighbour_steal_successes.store(0, Relaxed);
+        self.neighbour_steal_failures.store(0, Relaxed);
+        self.steps.store(0, Relaxed);
+        self.timed_steps.store(0, Relaxed);
+        self.step_time.store(0, Relaxed);
+        self.steal_wait.store(0, Relaxed);
+        self.steal_latency.store(0, Relaxed);
+    }
+}
+
+// The counters of all threads of a pool
+struct Registry {
+    threads: Vec<CachePadded<Counters>>,
+    // overrides the global switch for this pool
+    enabled: Option<bool>,
+}
+
+impl Registry {
+    fn new(num_threads: usize, enabled: Option<bool>) -> Self {
+        Registry {
+            threads: (0..num_threads.max(1))
+                .map(|_| CachePadded::new(Counters::new()))
+                .collect(),
+            enabled,
+        }
+    }
+    fn counters(&self) -> &Counters {
+        let index = crate::rayon::current_thread_index().unwrap_or(0);
+        &self.threads[index % self.threads.len()]
+    }
+    fn snapshot(&self) -> Statistics {
+        Statistics {
+            threads: self.threads.iter().map(|c| c.snapshot()).collect(),
+        }
+    }
+    fn reset(&self) {
+        self.threads.iter().for_each(|c| c.reset());
+    }
+}
+
+lazy_static! {
+    static ref GLOBAL: Arc<Registry> = Arc::new(Registry::new(num_cpus::get(), None));
+    static ref ENABLED: AtomicBool = AtomicBool::new(cfg!(feature = "statistics"));
+    static ref SAMPLE_RATE: AtomicUsize = AtomicUsize::new(16);
+}
+thread_local! {
+    static REGISTRY: RefCell<Option<Arc<Registry>>> = RefCell::new(None);
+    // steps since we timed the last one
+    static UNTIMED_STEPS: Cell<usize> = Cell::new(0);
+}
+
+/// Pools built with `AdaptivePoolBuilder::statistics` don't care about this.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Relaxed);
+}
+pub fn enabled() -> bool {
+    REGISTRY
+        .with(|r| r.borrow().as_ref().and_then(|r| r.enabled))
+        .unwrap_or_else(|| ENABLED.load(Relaxed))
+}
+/// Time every `rate`-th step, 1 times all of them.
+pub fn set_sample_rate(rate: usize) {
+    assert!(rate > 0);
+    SAMPLE_RATE.store(rate, Relaxed);
+}
+
+// Give this to the start handler of a pool, every worker then counts into the same registry
+pub(crate) fn start_handler(
+    num_threads: usize,
+    enabled: Option<bool>,
+) -> impl Fn(usize) + Send + Sync {
+    let registry = Arc::new(Registry::new(num_threads, enabled));
+    move |_| REGISTRY.with(|r| *r.borrow_mut() = Some(registry.clone()))
+}
+
+fn registry() -> Arc<Registry> {
+    REGISTRY.with(|r| r.borrow().clone()).unwrap_or_else(|| GLOBAL.clone())
+}
+
+fn with_counters(f: impl FnOnce(&Counters)) {
+    REGISTRY.with(|r| match &*r.borrow() {
+        Some(registry) => f(registry.counters()),
+        None => f(GLOBAL.counters()),
+    })
+}
+
+pub(crate) fn record_split(steal_counter: usize) {
+    with_counters(|c| {
+        c.splits.fetch_add(1, Relaxed);
+        c.steal_counter.fetch_add(steal_counter, Relaxed);
+        c.histogram[steal_counter.min(HISTOGRAM_SIZE - 1)].fetch_add(1, Relaxed);
+    })
+}
+
+// Who got a steal request, as seen by the topology
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Victim {
+    Local,
+    Remote,
+    // a thread of our own group we asked after a remote victim didn't answer, the pool
+    // doesn't steal from it right away
+    Neighbour,
+}
+
+pub(crate) fn record_steal(success: bool, victim: Victim) {
+    with_counters(|c| {
+        let (successes, failures) = match victim {
+            Victim::Neighbour => (&c.neighbour_steal_successes, &c.neighbour_steal_failures),
+            _ => (&c.steal_successes, &c.steal_failures),
+        };
+        if success {
+            successes.fetch_add(1, Relaxed);
+            if victim == Victim::Remote {
+                c.remote_steal_successes.fetch_add(1, Relaxed);
+            }
+        } else {
+            failures.fetch_add(1, Relaxed);
+            if victim == Victim::Remote {
+                c.remote_steal_failures.fetch_add(1, Relaxed);
+            }
+        }
+    })
+}
+
+// The current values of a thread using StealStrategy::Adaptive
+pub(crate) fn record_tuning(wait: Duration, latency: Duration) {
+    with_counters(|c| {
+        c.steal_wait.store(wait.as_nanos() as usize, Relaxed);
+        c.steal_latency.store(latency.as_nanos() as usize, Relaxed);
+    })
+}
+
+// For steps we timed anyway
+pub(crate) fn record_step(time: Duration) {
+    with_counters(|c| {
+        c.steps.fetch_add(1, Relaxed);
+        c.timed_steps.fetch_add(1, Relaxed);
+        c.step_time.fetch_add(time.as_nanos() as usize, Relaxed);
+    })
+}
+
+// Runs a step and counts it, only some of them get timed
+pub(crate) fn sampled_step<R>(step: impl FnOnce() -> R) -> R {
+    let sample = UNTIMED_STEPS.with(|untimed| {
+        let sample = untimed.get() + 1 >= SAMPLE_RATE.load(Relaxed);
+        untimed.set(if sample { 0 } else { untimed.get() + 1 });
+        sample
+    });
+    if sample {
+        let start = Instant::now();
+        let result = step();
+        record_step(start.elapsed());
+        result
+    } else {
+        let result = step();
+        with_counters(|c| {
+            c.steps.fetch_add(1, Relaxed);
+        });
+        result
+    }
+}
+
+/// Statistics of the pool we're running in (or the global ones outside of our pools).
+pub fn current() -> Statistics {
+    registry().snapshot()
+}
+
+/// Start counting from zero again, for the pool we're running in.
+pub fn reset_current() {
+    registry().reset()
+}
+
+/// Reset the statistics, run `op` and return what happened in the meantime. Other things
+/// running in the same pool at the same time get counted as well.
+pub fn measure<R>(op: impl FnOnce() -> R) -> (R, Statistics) {
+    let registry = registry();
+    registry.reset();
+    let result = op();
+    (result, registry.snapshot())
+}
+
+/// Statistics of all threads of `pool`.
+pub fn of(pool: &AdaptivePool) -> Statistics {
+    pool.install(current)
+}
+
+pub fn reset(pool: &AdaptivePool) {
+    pool.install(reset_current)
+}
+
+/// What one worker thread did.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ThreadStatistics {
+    /// how often we split a task for thieves
+    pub splits: usize,
+    /// sum of the steal counters we split for
+    pub steal_counter: usize,
+    /// how often we split for a steal counter of 0, 1, 2, ... (the last bucket is for more)
+    pub steal_counter_histogram: Vec<usize>,
+    /// steal requests of this thread that got answered
+    pub steal_successes: usize,
+    /// steal requests of this thread we gave up on
+    pub steal_failures: usize,
+    /// the part of the successes and failures with victims in another group of the topology
+    pub remote_steal_successes: usize,
+    pub remote_steal_failures: usize,
+    /// requests to a thread of our own group after a remote victim didn't answer, they're not
+    /// part of the successes and failures above
+    pub neighbour_steal_successes: usize,
+    pub neighbour_steal_failures: usize,
+    pub steps: usize,
+    /// the steps we timed, all the step times are for these only
+    pub timed_steps: usize,
+    pub step_time: Duration,
+    /// how long we wait for victims with `StealStrategy::Adaptive` (0 with other strategies)
+    pub steal_wait: Duration,
+    /// how long victims took to answer on average, as seen by `StealStrategy::Adaptive`
+    pub steal_latency: Duration,
+}
+
+impl ThreadStatistics {
+    pub fn average_steal_counter(&self) -> f64 {
+        if self.splits == 0 {
+            0.0
+        } else {
+            self.steal_counter as f64 / self.splits as f64
+        }
+    }
+    pub fn average_step_time(&self) -> Duration {
+        if self.timed_steps == 0 {
+            Duration::from_nanos(0)
+        } else {
+            Duration::from_nanos(self.step_time.as_nanos() as u64 / self.timed_steps as u64)
+        }
+    }
+    fn add(&mut self, other: &ThreadStatistics) {
+        self.splits += other.splits;
+        self.steal_counter += other.steal_counter;
+        let len = self
+            .steal_counter_histogram
+            .len()
+            .max(other.steal_counter_histogram.len());
+        self.steal_counter_histogram.resize(len, 0);
+        for (h, o) in self
+            .steal_counter_histogram
+            .iter_mut()
+            .zip(&other.steal_counter_histogram)
+        {
+            *h += o;
+        }
+        self.steal_successes += other.steal_successes;
+        self.steal_failures += other.steal_failures;
+        self.remote_steal_successes += other.remote_steal_successes;
+        self.remote_steal_failures += other.remote_steal_failures;
+        self.neighbour_steal_successes += other.neighbour_steal_successes;
+        self.neighbour_steal_failures += other.neighbour_steal_failures;
+        self.steps += other.steps;
+        self.timed_steps += other.timed_steps;
+        self.step_time += other.step_time;
+        self.steal_wait += other.steal_wait;
+        self.steal_latency += other.steal_latency;
+    }
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"splits\":{},\"steal_counter\":{},\"steal_counter_histogram\":[{}],\
+             \"steal_successes\":{},\"steal_failures\":{},\"remote_steal_successes\":{},\
+             \"remote_steal_failures\":{},\"neighbour_steal_successes\":{},\
+             \"neighbour_steal_failures\":{},\"steps\":{},\"timed_steps\":{},\
+             \"step_time_ns\":{},\"steal_wait_ns\":{},\"steal_latency_ns\":{}}}",
+            self.splits,
+            self.steal_counter,
+            self.histogram_string(","),
+            self.steal_successes,
+            self.steal_failures,
+            self.remote_steal_successes,
+            self.remote_steal_failures,
+            self.neighbour_steal_successes,
+            self.neighbour_steal_failures,
+            self.steps,
+            self.timed_steps,
+            self.step_time.as_nanos(),
+            self.steal_wait.as_nanos(),
+            self.steal_latency.as_nanos()
+        )
+    }
+    fn to_csv(&self, thread: &str) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}\n",
+            thread,
+            self.splits,
+            self.steal_counter,
+            self.steal_successes,
+            self.steal_failures,
+            self.remote_steal_successes,
+            self.remote_steal_failures,
+            self.neighbour_steal_successes,
+            self.neighbour_steal_failures,
+            self.steps,
+            self.timed_steps,
+            self.step_time.as_nanos(),
+            self.steal_wait.as_nanos(),
+            self.steal_latency.as_nanos(),
+            self.histogram_string(";")
+        )
+    }
+    // without the empty buckets at the end
+    fn histogram_string(&self, separator: &str) -> String {
+        let len = self
+            .steal_counter_histogram
+            .iter()
+            .rposition(|&h| h != 0)
+            .map_or(0, |i| i + 1);
+        self.steal_counter_histogram[..len]
+            .iter()
+            .map(|h| h.to_string())
+            .collect::<Vec<_>>()
+            .join(separator)
+    }
+}
+
+/// A snapshot of the statistics of a pool, one entry per worker thread.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Statistics {
+    pub threads: Vec<ThreadStatistics>,
+}
+
+impl Statistics {
+    /// Everything added up over all threads, except for the tuned steal wait and latency
+    /// which are averaged over the threads that tuned them.
+    pub fn total(&self) -> ThreadStatistics {
+        let mut total = ThreadStatistics::default();
+        self.threads.iter().for_each(|t| total.add(t));
+        let tuned = self
+            .threads
+            .iter()
+            .filter(|t| t.steal_wait.as_nanos() != 0)
+            .count();
+        if tuned != 0 {
+            total.steal_wait /= tuned as u32;
+            total.steal_latency /= tuned as u32;
+        }
+        total
+    }
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"total\":{},\"threads\":[{}]}}",
+            self.total().to_json(),
+            self.threads
+                .iter()
+                .map(|t| t.to_json())
+                .collect::<Vec<_>>()
+                .join(",")
+        )
+    }
+    /// One line per thread and one for the total, the histogram is separated by `;`.
+    pub fn to_csv(&self) -> String {
+        let mut csv = "thread,splits,steal_counter,steal_successes,steal_failures,\
+                       remote_steal_successes,remote_steal_failures,neighbour_steal_successes,\
+                       neighbour_steal_failures,steps,timed_steps,step_time_ns,steal_wait_ns,\
+                       steal_latency_ns,steal_counter_histogram\n"
+            .to_string();
+        for (i, t) in self.threads.iter().enumerate() {
+            csv += &t.to_csv(&i.to_string());
+        }
+        csv + &self.total().to_csv("total")
+    }
+}
+
+impl fmt::Display for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.total();
+        writeln!(f, "Sum of steals: {}", total.splits)?;
+        writeln!(f, "Steal Counter: {}", total.steal_counter)?;
+        writeln!(f, "Average Steal Counter: {}", total.average_steal_counter())?;
+        writeln!(f, "Steal Counter histogram: [{}]", total.histogram_string(", "))?;
+        writeln!(f, "Successful steals: {}", total.steal_successes)?;
+        writeln!(f, "Failed steals: {}", total.steal_failures)?;
+        writeln!(
+            f,
+            "Remote steals: {} successful, {} failed",
+            total.remote_steal_successes, total.remote_steal_failures
+        )?;
+        writeln!(
+            f,
+            "Neighbours asked instead: {} successful, {} failed",
+            total.neighbour_steal_successes, total.neighbour_steal_failures
+        )?;
+        writeln!(f, "Steps: {}", total.steps)?;
+        writeln!(f, "Timed steps: {}", total.timed_steps)?;
+        writeln!(f, "Times: {}", total.step_time.as_nanos())?;
+        writeln!(f, "Avg step time: {} nanos", total.average_step_time().as_nanos())?;
+        if total.steal_wait.as_nanos() != 0 {
+            writeln!(f, "Adaptive steal wait: {} nanos", total.steal_wait.as_nanos())?;
+            writeln!(f, "Steal latency: {} nanos", total.steal_latency.as_nanos())?;
+        }
+        for (i, t) in self.threads.iter().enumerate() {
+            writeln!(
+                f,
+                "Thread {}: {} steals, {} successful / {} failed steal requests, {} steps",
+                i, t.splits, t.steal_successes, t.steal_failures, t.steps
+            )?;
+            if t.steal_wait.as_nanos() != 0 {
+                writeln!(f, "  waiting {} nanos for victims", t.steal_wait.as_nanos())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_statistics() {
+    use crate::rayon::AdaptivePoolBuilder;
+    use crate::task::Task;
+    // takes exactly that many steps
+    struct Countdown(usize);
+    impl Task for Countdown {
+        fn step(&mut self) {
+            self.0 -= 1;
+        }
+        fn can_split(&self) -> bool {
+            false
+        }
+        fn is_finished(&self) -> bool {
+            self.0 == 0
+        }
+        fn split(&mut self, _runner: impl FnMut(&mut Vec<&mut Self>), _steal_counter: usize) {
+            unreachable!()
+        }
+        fn fuse(&mut self, _other: &mut Self) {}
+    }
+    let builder = |num_threads| {
+        AdaptivePoolBuilder::new()
+            .num_threads(num_threads)
+            .strategy(8)
+    };
+    let pool = builder(4).statistics(true).build();
+    let other = builder(2).statistics(true).build();
+    let disabled = builder(2).statistics(false).build();
+    let stats = pool.install(|| measure(|| Countdown(1000).run()).1);
+    assert_eq!(stats.threads.len(), 4);
+    assert_eq!(stats.total().steps, 1000);
+    // idle threads keep failing to steal, but no tasks run any more
+    let (total, now) = (stats.total(), of(&pool).total());
+    assert_eq!((now.steps, now.splits), (total.steps, total.splits));
+    // the other pool didn't do anything
+    assert_eq!(of(&other).total().steps, 0);
+    assert!(stats.to_json().starts_with("{\"total\":{\"splits\":"));
+    assert_eq!(stats.to_csv().lines().count(), 4 + 2);
+    // nothing split, that's no steal counter rather than NaN
+    assert_eq!(total.average_steal_counter(), 0.0);
+    assert!(!stats.to_string().contains("NaN"));
+    reset(&pool);
+    assert_eq!(of(&pool).total().steps, 0);
+    disabled.install(|| Countdown(1000).run());
+    assert_eq!(of(&disabled).total().steps, 0);
+}
@@ -1,91 +1,507 @@
+use crate::polling::{self, Detection};
+use crate::statistics::{self, Victim};
+use crate::topology::Topology;
 use crossbeam_utils::{Backoff, CachePadded};
+use std::cell::{Cell, RefCell};
+use std::fmt;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const BITS: usize = std::mem::size_of::<usize>() * 8;
+
+// The steal requests of all threads of one pool. Every thread has a bitset of the thieves
+// that want to steal from it, with one bit per thread of the pool, so it takes more than one
+// word if there are more than 64 threads. Every word gets its own cache line, so a thief
+// asking one victim doesn't slow down all the others checking theirs.
+struct StealState {
+    num_threads: usize,
+    words: usize,
+    // the words of victim v are at v * words..(v + 1) * words
+    requests: Vec<CachePadded<AtomicUsize>>,
+}
+impl StealState {
+    fn new(num_threads: usize) -> Self {
+        let num_threads = num_threads.max(1);
+        let words = (num_threads + BITS - 1) / BITS;
+        StealState {
+            num_threads,
+            words,
+            requests: (0..num_threads * words)
+                .map(|_| CachePadded::new(AtomicUsize::new(0)))
+                .collect(),
+        }
+    }
+    fn bitset(&self, victim: usize) -> &[CachePadded<AtomicUsize>] {
+        &self.requests[victim * self.words..(victim + 1) * self.words]
+    }
+    // the word and bit of `thief` in the bitset of `victim`
+    fn request(&self, victim: usize, thief: usize) -> (&AtomicUsize, usize) {
+        (&self.bitset(victim)[thief / BITS], 1 << (thief % BITS))
+    }
+    fn count(&self, victim: usize) -> usize {
+        let steal_counter = self
+            .bitset(victim)
+            .iter()
+            .map(|word| word.load(Ordering::Relaxed).count_ones() as usize)
+            .sum();
+        std::cmp::min(steal_counter, self.num_threads - 1)
+    }
+    fn reset(&self, victim: usize) {
+        for word in self.bitset(victim) {
+            word.store(0, Ordering::Relaxed);
+        }
+    }
+}
 
 lazy_static! {
     static ref NUM_THREADS: usize = num_cpus::get();
-    static ref V: Vec<CachePadded<AtomicUsize>> = (0..*NUM_THREADS)
-        .map(|_| CachePadded::new(AtomicUsize::new(0)))
-        .collect();
+    // for pools that we didn't build ourselves
+    static ref GLOBAL: Arc<StealState> = Arc::new(StealState::new(*NUM_THREADS));
 }
-#[cfg(feature = "statistics")]
-lazy_static! {
-    pub static ref STEAL_SUCCESS: AtomicUsize = AtomicUsize::new(0);
-    pub static ref STEAL_FAIL: AtomicUsize = AtomicUsize::new(0);
+thread_local! {
+    static STATE: RefCell<Option<Arc<StealState>>> = RefCell::new(None);
+}
+
+// Give this to the start handler of a pool, so its threads don't share steal requests with
+// any other pool
+pub(crate) fn start_handler(num_threads: usize) -> impl Fn(usize) + Send + Sync {
+    let state = Arc::new(StealState::new(num_threads));
+    move |_| STATE.with(|s| *s.borrow_mut() = Some(state.clone()))
+}
+
+// How many threads the global steal requests are for
+#[cfg(feature = "logs")]
+pub(crate) fn global_num_threads() -> usize {
+    *NUM_THREADS
+}
+
+fn with_state<R>(f: impl FnOnce(&StealState) -> R) -> R {
+    STATE.with(|s| match &*s.borrow() {
+        Some(state) => f(state),
+        None => f(&GLOBAL),
+    })
+}
+
+/// How a thief waits for the victim to answer its steal request before giving up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StealStrategy {
+    /// Check this many times, with a fixed short pause in between
+    Spin(usize),
+    /// Check this many times, pausing exponentially longer (up to a limit)
+    Exponential(usize),
+    /// Like Exponential, but yield the thread once we waited a bit
+    Snooze(usize),
+    /// Yield the thread between checks
+    Yield(usize),
+    /// Keep checking until the time is up
+    Timed(Duration),
+    /// Exponential, and the more threads we have the less we wait
+    Optimized,
+    /// Exponential with the backoffs `steal(0, _)` always used, a bit more than Optimized for
+    /// up to 8 threads and a bit less above
+    Legacy,
+    /// Every thread tunes how long it waits from how long victims took to answer and how
+    /// many of its steal requests got answered at all
+    Adaptive,
+}
+
+// What a thread learned about its steal requests so far, for StealStrategy::Adaptive
+#[derive(Debug, Clone, Copy)]
+struct Tuning {
+    // average time until a victim answered, in nanoseconds
+    latency: f64,
+    // average fraction of answered steal requests
+    success_rate: f64,
+}
+
+impl Tuning {
+    const MIN_WAIT: f64 = 100.0;
+    const MAX_WAIT: f64 = 100_000.0;
+    // weight of the newest observation in the averages
+    const ALPHA: f64 = 0.125;
+
+    // We wait about as long as victims usually take to answer, a bit more if that usually
+    // works out. If most requests fail waiting is wasted, so we wait less.
+    fn wait(&self) -> Duration {
+        let wait = self.latency * (1.0 + self.success_rate);
+        Duration::from_nanos(wait.max(Self::MIN_WAIT).min(Self::MAX_WAIT) as u64)
+    }
+    fn update(&mut self, latency: Option<Duration>) {
+        let success = if let Some(latency) = latency {
+            self.latency += Self::ALPHA * (latency.as_nanos() as f64 - self.latency);
+            1.0
+        } else {
+            0.0
+        };
+        self.success_rate += Self::ALPHA * (success - self.success_rate);
+    }
 }
-#[cfg(feature = "statistics")]
-use std::cell::RefCell;
+
 thread_local! {
-#[cfg(feature = "statistics")]
-    pub static LAST_VICTIM: RefCell<usize>= RefCell::new(0);
+    static TUNING: Cell<Tuning> = Cell::new(Tuning {
+        latency: 1000.0,
+        success_rate: 0.5,
+    });
 }
 
-pub fn optimized_steal(victim: usize) -> Option<()> {
-    let num_threads = rayon::current_num_threads();
-    let backoffs = match num_threads {
+impl StealStrategy {
+    // Wait until `answered` or we give up, true if we got an answer
+    fn wait(&self, mut answered: impl FnMut() -> bool) -> bool {
+        match *self {
+            StealStrategy::Spin(checks) => (0..checks).any(|_| {
+                std::hint::spin_loop();
+                answered()
+            }),
+            StealStrategy::Exponential(checks) => {
+                let backoff = Backoff::new();
+                (0..checks).any(|_| {
+                    backoff.spin();
+                    answered()
+                })
+            }
+            StealStrategy::Snooze(checks) => {
+                let backoff = Backoff::new();
+                (0..checks).any(|_| {
+                    backoff.snooze();
+                    answered()
+                })
+            }
+            StealStrategy::Yield(checks) => (0..checks).any(|_| {
+                std::thread::yield_now();
+                answered()
+            }),
+            StealStrategy::Timed(time) => {
+                let start = Instant::now();
+                while start.elapsed() < time {
+                    if answered() {
+                        return true;
+                    }
+                    std::hint::spin_loop();
+                }
+                false
+            }
+            StealStrategy::Optimized => {
+                StealStrategy::Exponential(optimized_backoffs()).wait(answered)
+            }
+            StealStrategy::Legacy => StealStrategy::Exponential(legacy_backoffs()).wait(answered),
+            StealStrategy::Adaptive => {
+                let mut tuning = TUNING.with(|t| t.get());
+                let start = Instant::now();
+                let success = StealStrategy::Timed(tuning.wait()).wait(answered);
+                tuning.update(if success { Some(start.elapsed()) } else { None });
+                TUNING.with(|t| t.set(tuning));
+                if statistics::enabled() {
+                    statistics::record_tuning(
+                        tuning.wait(),
+                        Duration::from_nanos(tuning.latency as u64),
+                    );
+                }
+                success
+            }
+        }
+    }
+}
+
+// How many backoffs StealStrategy::Optimized waits
+fn optimized_backoffs() -> usize {
+    match crate::rayon::current_num_threads() {
         // the more threads we have the less we should wait trying to steal. Numbers are purely
         // experimental and might not be optimal for many cases
         1 => panic!("Can't steal from myself"), // What are we even doing here?
         2..=4 => 8,
-        4..=8 => 4,
+        5..=8 => 4,
         9..=16 => 2,
         _ => 1,
-    };
-    steal(backoffs, victim)
+    }
 }
 
-pub fn steal(mut backoffs: usize, victim: usize) -> Option<()> {
-    #[cfg(feature = "statistics")]
-    LAST_VICTIM.with(|v| {
-        *v.borrow_mut() = victim;
-    });
-    let thread_index = rayon::current_thread_index().unwrap();
-    let thread_index = 1 << thread_index;
-    V[victim].fetch_or(thread_index, Ordering::Relaxed);
-
-    if backoffs == 0 {
-        let num_threads = rayon::current_num_threads();
-        backoffs = match num_threads {
-            1 => panic!("Can't steal from myself"), // What are we even doing here?
-            2..=8 => 6,
-            9..=12 => 4,
-            _ => 1,
-        };
+// How many backoffs StealStrategy::Legacy waits
+fn legacy_backoffs() -> usize {
+    match crate::rayon::current_num_threads() {
+        1 => panic!("Can't steal from myself"), // What are we even doing here?
+        2..=8 => 6,
+        9..=12 => 4,
+        _ => 1,
     }
+}
 
-    let backoff = Backoff::new();
-    let mut c: usize;
-    for _ in 0..backoffs {
-        backoff.spin(); // spin or snooze()?
+impl StealStrategy {
+    /// For victims in another group of the topology we only wait a quarter as long. The
+    /// adaptive strategy tunes itself anyway.
+    pub fn remote(&self) -> Self {
+        let less = |checks: usize| (checks / 4).max(1);
+        match *self {
+            StealStrategy::Spin(checks) => StealStrategy::Spin(less(checks)),
+            StealStrategy::Exponential(checks) => StealStrategy::Exponential(less(checks)),
+            StealStrategy::Snooze(checks) => StealStrategy::Snooze(less(checks)),
+            StealStrategy::Yield(checks) => StealStrategy::Yield(less(checks)),
+            StealStrategy::Timed(time) => StealStrategy::Timed(time / 4),
+            StealStrategy::Optimized => StealStrategy::Exponential(less(optimized_backoffs())),
+            StealStrategy::Legacy => StealStrategy::Exponential(less(legacy_backoffs())),
+            StealStrategy::Adaptive => StealStrategy::Adaptive,
+        }
+    }
+}
 
-        // wait until the victim has taken the value, check regularly
-        c = V[victim].load(Ordering::Relaxed);
-        if c == 0 {
-            #[cfg(feature = "statistics")]
-            STEAL_SUCCESS.fetch_add(1, Ordering::Relaxed);
+// That's what the number of backoffs always meant, 0 was for "choose yourself" with the table
+// of `steal`
+impl From<usize> for StealStrategy {
+    fn from(backoffs: usize) -> Self {
+        if backoffs == 0 {
+            StealStrategy::Legacy
+        } else {
+            StealStrategy::Exponential(backoffs)
+        }
+    }
+}
+
+impl fmt::Display for StealStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StealStrategy::Spin(checks) => write!(f, "spin-{}", checks),
+            StealStrategy::Exponential(checks) => write!(f, "{}", checks),
+            StealStrategy::Snooze(checks) => write!(f, "snooze-{}", checks),
+            StealStrategy::Yield(checks) => write!(f, "yield-{}", checks),
+            StealStrategy::Timed(time) => write!(f, "timed-{:?}", time),
+            StealStrategy::Optimized => write!(f, "optimized"),
+            StealStrategy::Legacy => write!(f, "legacy"),
+            StealStrategy::Adaptive => write!(f, "adaptive"),
+        }
+    }
+}
+
+pub fn optimized_steal(victim: usize) -> Option<()> {
+    steal_with(StealStrategy::Optimized, victim)
+}
+
+pub fn steal(backoffs: usize, victim: usize) -> Option<()> {
+    steal_with(backoffs.into(), victim)
+}
+
+pub fn steal_with(strategy: StealStrategy, victim: usize) -> Option<()> {
+    send_request(strategy, victim, Victim::Local)
+}
 
-            return Some(());
+/// Like `steal_with`, but we don't wait as long for victims far away from us. If one of them
+/// doesn't answer in time we ask a thread in our own group instead.
+pub fn steal_with_topology(
+    strategy: StealStrategy,
+    topology: &Topology,
+    victim: usize,
+) -> Option<()> {
+    let thief = crate::rayon::current_thread_index().unwrap();
+    if topology.is_local(thief, victim) {
+        return send_request(strategy, victim, Victim::Local);
+    }
+    if send_request(strategy.remote(), victim, Victim::Remote).is_some() {
+        return Some(());
+    }
+    // Some means "steal from `victim`", so we can't report the neighbour's answer. If it split
+    // its task the piece sits in its deque, where the pool looks for work anyway.
+    if let Some(neighbour) = topology.random_neighbour(thief) {
+        send_request(strategy, neighbour, Victim::Neighbour);
+    }
+    None
+}
+
+fn send_request(strategy: StealStrategy, victim: usize, kind: Victim) -> Option<()> {
+    let thief = crate::rayon::current_thread_index().unwrap();
+    with_state(|state| {
+        // A pool we didn't build can be bigger than the global state, these threads just
+        // don't get to send steal requests
+        if victim >= state.num_threads || thief >= state.num_threads {
+            return None;
         }
+        let (word, bit) = state.request(victim, thief);
+        steal_from(word, bit, strategy, kind)
+    })
+}
+
+fn steal_from(word: &AtomicUsize, bit: usize, strategy: StealStrategy, kind: Victim) -> Option<()> {
+    word.fetch_or(bit, Ordering::Relaxed);
+
+    // wait until the victim has taken our request, check regularly
+    if strategy.wait(|| word.load(Ordering::Relaxed) & bit == 0) {
+        if statistics::enabled() {
+            statistics::record_steal(true, kind);
+        }
+
+        return Some(());
     }
 
-    V[victim].fetch_and(!thread_index, Ordering::Relaxed);
+    word.fetch_and(!bit, Ordering::Relaxed);
 
-    #[cfg(feature = "statistics")]
-    STEAL_FAIL.fetch_add(1, Ordering::Relaxed);
+    if statistics::enabled() {
+        statistics::record_steal(false, kind);
+    }
 
     None
 }
+/// How many thieves want a piece of our work. In pools that poll for idle threads that's
+/// just how many of them are idle.
 pub fn get_my_steal_count() -> usize {
-    if let Some(thread_index) = rayon::current_thread_index() {
-        let steal_counter = V[thread_index].load(Ordering::Relaxed);
-        let steal_counter = steal_counter.count_ones() as usize;
-        let steal_counter = std::cmp::min(steal_counter, *NUM_THREADS - 1);
-        steal_counter
+    if polling::detection() == Detection::Polling {
+        return polling::idle_threads();
+    }
+    if let Some(thread_index) = crate::rayon::current_thread_index() {
+        with_state(|state| {
+            if thread_index < state.num_threads {
+                state.count(thread_index)
+            } else {
+                0
+            }
+        })
     } else {
         0
     }
 }
 pub fn reset_my_steal_count() {
-    if let Some(thread_index) = rayon::current_thread_index() {
-        V[thread_index].store(0, Ordering::Relaxed);
+    if let Some(thread_index) = crate::rayon::current_thread_index() {
+        with_state(|state| {
+            if thread_index < state.num_threads {
+                state.reset(thread_index)
+            }
+        });
+    }
+}
+
+// Clears the steal requests we got if we unwind because of a panic, so nobody is left with
+// a stale steal counter
+pub(crate) struct ResetOnPanic;
+impl Drop for ResetOnPanic {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            reset_my_steal_count();
+        }
+    }
+}
+
+#[test]
+fn test_pools() {
+    use crate::rayon::AdaptivePoolBuilder;
+    let build = |num_threads| {
+        let builder = AdaptivePoolBuilder::new()
+            .num_threads(num_threads)
+            .without_steal_requests();
+        // stock rayon pools poll for idle threads instead
+        #[cfg(not(feature = "steal-callback"))]
+        let builder = builder.builtin();
+        builder.build()
+    };
+    // both pools have a thread 0, 1, ... but they must not see each other's steal requests
+    let pools = vec![build(2), build(3)];
+    assert_eq!(
+        pools[1].install(|| with_state(|state| state.num_threads)),
+        3
+    );
+    pools[0].install(|| {
+        with_state(|state| {
+            for victim in 0..2 {
+                let (word, bit) = state.request(victim, 1 - victim);
+                word.fetch_or(bit, Ordering::Relaxed);
+            }
+        })
+    });
+    assert_eq!(pools[0].install(get_my_steal_count), 1);
+    assert_eq!(pools[1].install(get_my_steal_count), 0);
+    assert_eq!(get_my_steal_count(), 0);
+}
+
+#[test]
+fn test_many_threads() {
+    use crate::rayon::AdaptivePoolBuilder;
+    let state = StealState::new(130);
+    for &thief in &[0, 64, 129] {
+        let (word, bit) = state.request(5, thief);
+        word.fetch_or(bit, Ordering::Relaxed);
     }
+    assert_eq!(state.count(5), 3);
+    assert_eq!(state.count(64), 0);
+    state.reset(5);
+    assert_eq!(state.count(5), 0);
+
+    // way more threads than cpus, and more than fit in one word
+    let builder = AdaptivePoolBuilder::new()
+        .num_threads(130)
+        .without_steal_requests();
+    #[cfg(not(feature = "steal-callback"))]
+    let builder = builder.builtin();
+    let pool = builder.build();
+    let steal_count = pool.install(|| {
+        let victim = crate::rayon::current_thread_index().unwrap();
+        with_state(|state| {
+            assert_eq!(state.num_threads, 130);
+            for &thief in &[0, 64, 129] {
+                if thief != victim {
+                    let (word, bit) = state.request(victim, thief);
+                    word.fetch_or(bit, Ordering::Relaxed);
+                }
+            }
+        });
+        get_my_steal_count()
+    });
+    assert!(steal_count >= 2);
+}
+
+#[test]
+fn test_strategies() {
+    let strategies = vec![
+        StealStrategy::Spin(10),
+        StealStrategy::Exponential(10),
+        StealStrategy::Snooze(10),
+        StealStrategy::Yield(10),
+        StealStrategy::Timed(Duration::from_micros(50)),
+        StealStrategy::Optimized,
+        StealStrategy::Legacy,
+        StealStrategy::Adaptive,
+    ];
+    let pool = crate::rayon::get_custom_thread_pool(2, 0);
+    pool.install(|| {
+        for strategy in &strategies {
+            let mut checks = 0;
+            assert!(!strategy.wait(|| {
+                checks += 1;
+                false
+            }));
+            assert!(checks > 0);
+            // answered on the third check
+            let mut checks = 0;
+            assert!(strategy.wait(|| {
+                checks += 1;
+                checks == 3
+            }));
+        }
+    });
+    // victims always answer after 5 micros
+    let mut tuning = Tuning {
+        latency: 1000.0,
+        success_rate: 0.5,
+    };
+    for _ in 0..100 {
+        tuning.update(Some(Duration::from_micros(5)));
+    }
+    assert!((tuning.wait().as_nanos() as i64 - 10_000).abs() < 100);
+    // and then never, so there's no point in waiting longer than they used to take
+    for _ in 0..100 {
+        tuning.update(None);
+    }
+    assert!((tuning.wait().as_nanos() as i64 - 5_000).abs() < 100);
+
+    assert_eq!(StealStrategy::from(0), StealStrategy::Legacy);
+    // 6 backoffs for 2 threads, Optimized would wait 8
+    pool.install(|| {
+        assert_eq!(
+            StealStrategy::Legacy.remote(),
+            StealStrategy::Exponential(1)
+        );
+        assert_eq!(
+            StealStrategy::Optimized.remote(),
+            StealStrategy::Exponential(2)
+        );
+    });
+    assert_eq!(StealStrategy::Spin(8).remote(), StealStrategy::Spin(2));
+    assert_eq!(StealStrategy::Yield(2).remote(), StealStrategy::Yield(1));
+    assert_eq!(StealStrategy::from(6).to_string(), "6");
+    assert_eq!(StealStrategy::Snooze(4).to_string(), "snooze-4");
 }
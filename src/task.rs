@@ -1,44 +1,62 @@
+use crate::polling::{Busy, Idle, Pending};
 use crate::rayon;
 use crate::steal;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-#[cfg(feature = "statistics")]
-use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
-#[cfg(feature = "statistics")]
 lazy_static! {
-    pub static ref SUCCESSFUL_STEALS: AtomicUsize = AtomicUsize::new(0);
-    pub static ref TOTAL_STEAL_COUNTER: AtomicUsize = AtomicUsize::new(0);
-    pub static ref STEPS: AtomicUsize = AtomicUsize::new(0);
-    pub static ref STEP_TIME: AtomicUsize = AtomicUsize::new(0);
-
-}
-#[cfg(feature = "statistics")]
-pub fn reset_statistics(){
-    SUCCESSFUL_STEALS.store(0, Relaxed);
-    TOTAL_STEAL_COUNTER.store(0, Relaxed);
-    steal::STEAL_SUCCESS.store(0, Relaxed);
-    steal::STEAL_FAIL.store(0, Relaxed);
-    STEPS.store(0, Relaxed);
-    STEP_TIME.store(0, Relaxed);
-
-}
-
-#[cfg(feature = "statistics")]
-pub fn print_statistics() {
-    let steals = SUCCESSFUL_STEALS.load(Relaxed);
-    let total = TOTAL_STEAL_COUNTER.load(Relaxed);
-    let successes = steal::STEAL_SUCCESS.load(Relaxed);
-    let fails = steal::STEAL_FAIL.load(Relaxed);
-    let steps = STEPS.load(Relaxed);
-    let step_time = STEP_TIME.load(Relaxed);
-    println!("Sum of steals: {}", steals);
-    println!("Steal Counter: {}", total);
-    println!("Average Steal Counter: {}", total as f64 / steals as f64);
-    println!("Successful steals: {}", successes);
-    println!("Failed steals: {}", fails);
-    println!("Steps: {}", steps);
-    println!("Times: {}", step_time);
-    println!("Avg step time: {} nanos", step_time as f64 /steps as f64);
+    // Split into steal_counter pieces instead of two? The feature only sets the default
+    static ref MULTISPLIT: AtomicBool = AtomicBool::new(cfg!(feature = "multisplit"));
+    // how long a step of a task with a step size should take, in nanoseconds
+    static ref TARGET_STEP_TIME: AtomicU64 = AtomicU64::new(20_000);
+}
+thread_local! {
+    // what the pool we run in chose, see `AdaptivePoolBuilder::multisplit`
+    static POOL_MULTISPLIT: Cell<Option<bool>> = Cell::new(None);
+}
+/// Choose at runtime if steal requests split tasks into one piece per thief or just in two.
+/// That's only the default, pools built with `AdaptivePoolBuilder::multisplit` don't care.
+pub fn set_multisplit(multisplit: bool) {
+    MULTISPLIT.store(multisplit, Ordering::Relaxed);
+}
+pub fn multisplit() -> bool {
+    POOL_MULTISPLIT
+        .with(|m| m.get())
+        .unwrap_or_else(|| MULTISPLIT.load(Ordering::Relaxed))
+}
+// Give this to the start handler of a pool, None keeps the default
+pub(crate) fn start_handler(multisplit: Option<bool>) -> impl Fn(usize) + Send + Sync {
+    move |_| POOL_MULTISPLIT.with(|m| m.set(multisplit))
+}
+/// Tasks with a step size get calibrated so a step takes about this long. That's the time a
+/// thief has to wait at most (well, twice that) before we react to its steal request.
+pub fn set_target_step_time(time: Duration) {
+    TARGET_STEP_TIME.store(time.as_nanos() as u64, Ordering::Relaxed);
+}
+pub fn target_step_time() -> Duration {
+    Duration::from_nanos(TARGET_STEP_TIME.load(Ordering::Relaxed))
+}
+
+use crate::statistics;
 
+/// Lets pieces of a task stop each other early, e.g. once a search found something. A task
+/// returns it in `cancellation` and gives a clone to every piece it creates in `split`.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
 }
 
 pub trait Task: Sized + Send {
@@ -48,7 +66,7 @@ pub trait Task: Sized + Send {
             // get the first task (take from the front so we can fuse correctly in the end
             let task = tasks.remove(0);
             // run it
-            rayon::join(|| Self::runner(tasks), || task.run());
+            join_pieces(|| Self::runner(tasks), || task.run());
 
             // Finished doing all tasks, we need to fuse here
             // Grab the successor from the vector and fuse
@@ -66,37 +84,57 @@ pub trait Task: Sized + Send {
             }
         }
     }
-    fn runner_with<T: Task>(f: &mut impl Task, tasks: &mut Vec<&mut T>) {
-        if tasks.len() == 1 {
-            rayon::join(|| f.run(), || tasks[0].run());
-            return;
-        }
-        let mut right = tasks.pop().unwrap();
-        let left = tasks.pop().unwrap();
-        rayon::join(
+    // Like runner, but this thread first has to finish the nested work it got interrupted in
+    fn runner_nested(inner: &mut (dyn FnMut() + Send), tasks: &mut Vec<&mut Self>) {
+        let task = tasks.remove(0);
+        join_pieces(
             || {
                 steal::reset_my_steal_count();
-                f.run();
-                left.run()
+                inner();
+                task.run()
             },
-            || right.run(),
+            || Self::runner(tasks),
         );
-        left.fuse(&mut right);
-        if !tasks.is_empty() {
-            T::runner(tasks);
-            left.fuse(tasks.pop().unwrap());
+        if let Some(other) = tasks.pop() {
+            task.fuse(other);
         }
+        tasks.push(task);
     }
     fn run(&mut self) {
+        self.run_nested(&mut ());
+    }
+    fn run_with(&mut self, f: &mut impl Task) {
+        self.run_nested(&mut Nest::new(f, &mut ()));
+    }
+    // Like run, but steal requests can also split the tasks we're nested in, the outermost
+    // one first since it has the biggest pieces of work
+    fn run_nested(&mut self, outer: &mut dyn Enclosing) {
+        let _guard = steal::ResetOnPanic;
+        let _busy = Busy::enter();
         let work = self.work();
         let mut run_loop = || {
-            while !self.is_finished() {
+            while !self.is_finished() && !self.is_cancelled() {
                 let steal_counter = steal::get_my_steal_count();
-                if steal_counter != 0 && self.can_split() {
-                    self.split_run(steal_counter);
-                    continue;
+                if steal_counter != 0 {
+                    if outer.can_split() {
+                        outer.split_enclosing(&mut || self.run(), steal_counter);
+                        continue;
+                    }
+                    if self.can_split() {
+                        self.split_run(steal_counter);
+                        continue;
+                    }
+                }
+                match self.step_size() {
+                    Some(size) => {
+                        let elapsed = measured_step(|| self.step_nested(outer));
+                        // the last step is usually a short one, don't learn from it
+                        if !self.is_finished() {
+                            self.set_step_size(calibrate(size, elapsed));
+                        }
+                    }
+                    None => timed_step(|| self.step_nested(outer)),
                 }
-                self.do_step();
             }
         };
         if let Some((work_type, work_amount)) = work {
@@ -105,64 +143,512 @@ pub trait Task: Sized + Send {
             run_loop()
         }
     }
-    fn run_with(&mut self, f: &mut impl Task) {
+    fn step(&mut self);
+    // Tasks running nested tasks in their step should do it here with
+    // `inner.run_nested(&mut Nest::new(self, outer))`, so steal requests on any level can split
+    // us or anything around us. Move past the element the nested task works on before that, a
+    // split only gives away what's left of us and the interrupted element is finished by the
+    // nested task. This thread then runs the rest of our part before coming back, so the step
+    // has to cope with being finished in the meantime.
+    fn step_nested(&mut self, _outer: &mut dyn Enclosing) {
+        self.step();
+    }
+    fn split_run(&mut self, steal_counter: usize) {
+        self.split(Self::runner, split_count(steal_counter));
+    }
+    // Tasks that can do more or less work in a step tell us how much they do right now, and we
+    // adjust it until a step takes about `target_step_time()`. Pieces should inherit it.
+    fn step_size(&self) -> Option<usize> {
+        None
+    }
+    fn set_step_size(&mut self, _size: usize) {}
+
+    fn can_split(&self) -> bool;
+    fn work(&self) -> Option<(&'static str, usize)> {
+        None
+    }
+    fn is_finished(&self) -> bool;
+    // Tasks that can be stopped early return the token shared by all their pieces
+    fn cancellation(&self) -> Option<&CancellationToken> {
+        None
+    }
+    fn is_cancelled(&self) -> bool {
+        self.cancellation().map_or(false, |c| c.is_cancelled())
+    }
+    fn split(&mut self, runner: impl FnMut(&mut Vec<&mut Self>), steal_counter: usize);
+    fn fuse(&mut self, other: &mut Self);
+}
+
+/// The tasks a nested task runs in, as seen from inside.
+pub trait Enclosing {
+    fn can_split(&self) -> bool;
+    // Split the outermost task that can. `inner` is the interrupted work, it gets finished on
+    // this thread before we continue with our own part of the split task.
+    fn split_enclosing(&mut self, inner: &mut (dyn FnMut() + Send), steal_counter: usize);
+}
+// Not nested at all
+impl Enclosing for () {
+    fn can_split(&self) -> bool {
+        false
+    }
+    fn split_enclosing(&mut self, inner: &mut (dyn FnMut() + Send), _steal_counter: usize) {
+        inner();
+    }
+}
+
+/// One more level of nesting: `task` and everything around it.
+pub struct Nest<'a, T> {
+    task: &'a mut T,
+    outer: &'a mut dyn Enclosing,
+}
+impl<'a, T: Task> Nest<'a, T> {
+    pub fn new(task: &'a mut T, outer: &'a mut dyn Enclosing) -> Self {
+        Nest { task, outer }
+    }
+}
+impl<'a, T: Task> Enclosing for Nest<'a, T> {
+    fn can_split(&self) -> bool {
+        self.outer.can_split() || self.task.can_split()
+    }
+    fn split_enclosing(&mut self, inner: &mut (dyn FnMut() + Send), steal_counter: usize) {
+        if self.outer.can_split() {
+            self.outer.split_enclosing(inner, steal_counter);
+        } else {
+            self.task.split(
+                |tasks| T::runner_nested(inner, tasks),
+                split_count(steal_counter),
+            );
+        }
+    }
+}
+
+/// Alternative to `Task` for tasks that produce a result instead of keeping it in their state.
+/// Pieces get consumed when they're done, and the outputs of neighbouring pieces are combined
+/// (left to right) with `reduce`, so the output can be of a completely different type.
+pub trait OutputTask: Sized + Send {
+    type Output: Send;
+    fn runner(first: Self, mut rest: Vec<Self>) -> Self::Output {
+        if rest.is_empty() {
+            // last task, reset counter so stealers know they can steal now
+            steal::reset_my_steal_count();
+            return first.run();
+        }
+        let second = rest.remove(0);
+        let (right, left) = join_pieces(|| Self::runner(second, rest), || first.run());
+        Self::reduce(left, right)
+    }
+    fn run(mut self) -> Self::Output {
+        let _guard = steal::ResetOnPanic;
+        let _busy = Busy::enter();
         let work = self.work();
-        let mut run_loop = || {
-            while !self.is_finished() {
+        let run_loop = move || {
+            while !self.is_finished() && !self.is_cancelled() {
                 let steal_counter = steal::get_my_steal_count();
-                if steal_counter != 0 && (f.can_split() || self.can_split()) {
-                    self.split_run_with(steal_counter, f);
-                    continue;
+                if steal_counter != 0 && self.can_split() {
+                    let pieces = self.split(split_count(steal_counter));
+                    return Self::runner(self, pieces);
                 }
-                self.do_step();
+                timed_step(|| self.step());
             }
+            self.output()
         };
         if let Some((work_type, work_amount)) = work {
-            rayon::subgraph(work_type, work_amount, || run_loop())
+            rayon::subgraph(work_type, work_amount, run_loop)
         } else {
             run_loop()
         }
     }
-    #[cfg(not(feature = "statistics"))]
-    fn do_step(&mut self) {
+    fn step(&mut self);
+    fn can_split(&self) -> bool;
+    fn work(&self) -> Option<(&'static str, usize)> {
+        None
+    }
+    fn is_finished(&self) -> bool;
+    fn cancellation(&self) -> Option<&CancellationToken> {
+        None
+    }
+    fn is_cancelled(&self) -> bool {
+        self.cancellation().map_or(false, |c| c.is_cancelled())
+    }
+    // Give away parts of our work, returns the new pieces (all right of us) in order
+    fn split(&mut self, steal_counter: usize) -> Vec<Self>;
+    fn output(self) -> Self::Output;
+    fn reduce(left: Self::Output, right: Self::Output) -> Self::Output;
+}
 
+/// A `Task` whose steps can fail. `try_run` stops at the first error and returns it; if the
+/// task has a cancellation token the other pieces get cancelled, too.
+pub trait TryTask: Sized + Send {
+    type Error: Send;
+    fn try_runner(tasks: &mut Vec<&mut Self>) -> Result<(), Self::Error> {
+        if tasks.len() > 1 {
+            // get the first task (take from the front so we can fuse correctly in the end
+            let task = tasks.remove(0);
+            let (others, mine) = join_pieces(|| Self::try_runner(tasks), || task.try_run());
+            // errors of the left part come first
+            mine?;
+            others?;
+            if let Some(other) = tasks.pop() {
+                task.fuse(other);
+            }
+            tasks.push(task);
+            Ok(())
+        } else {
+            // last task, reset counter so stealers know they can steal now
+            steal::reset_my_steal_count();
+            match tasks.first_mut() {
+                Some(task) => task.try_run(),
+                None => Ok(()),
+            }
+        }
     }
-    #[cfg(feature = "statistics")]
-    fn do_step(&mut self){
-        let start = std::time::Instant::now();
-        self.step();
-        STEP_TIME.fetch_add(start.elapsed().as_nanos() as usize, Relaxed);
-        STEPS.fetch_add(1, Relaxed);
+    fn try_run(&mut self) -> Result<(), Self::Error> {
+        let _guard = steal::ResetOnPanic;
+        let _busy = Busy::enter();
+        while !self.is_finished() && !self.is_cancelled() {
+            let steal_counter = steal::get_my_steal_count();
+            if steal_counter != 0 && self.can_split() {
+                let mut result = Ok(());
+                self.split(
+                    |tasks| result = Self::try_runner(tasks),
+                    split_count(steal_counter),
+                );
+                result?;
+                continue;
+            }
+            if let Err(error) = timed_step(|| self.try_step()) {
+                if let Some(cancel) = self.cancellation() {
+                    cancel.cancel();
+                }
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+    fn try_step(&mut self) -> Result<(), Self::Error>;
+    fn can_split(&self) -> bool;
+    fn is_finished(&self) -> bool;
+    fn cancellation(&self) -> Option<&CancellationToken> {
+        None
+    }
+    fn is_cancelled(&self) -> bool {
+        self.cancellation().map_or(false, |c| c.is_cancelled())
+    }
+    fn split(&mut self, runner: impl FnMut(&mut Vec<&mut Self>), steal_counter: usize);
+    fn fuse(&mut self, other: &mut Self);
+}
+
+// Runs two pieces of a split task, `b` can get stolen. Waiting for the other one doesn't keep
+// this thread busy, the pieces count themselves when they run.
+fn join_pieces<A, B, RA, RB>(a: A, b: B) -> (RA, RB)
+where
+    A: FnOnce() -> RA + Send,
+    B: FnOnce() -> RB + Send,
+    RA: Send,
+    RB: Send,
+{
+    let pending = Pending::new();
+    let _idle = Idle::enter();
+    rayon::join(a, || {
+        drop(pending);
+        b()
+    })
+}
 
+// Counts the steal and gives back for how many thieves we should split
+fn split_count(steal_counter: usize) -> usize {
+    if statistics::enabled() {
+        statistics::record_split(steal_counter);
     }
 
-    fn step(&mut self);
-    fn split_run_with(&mut self, steal_counter: usize, f: &mut impl Task) {
-        if f.can_split() {
-            f.split(|x| Self::runner_with(self, x), 1);
+    if multisplit() {
+        steal_counter
+    } else {
+        1
+    }
+}
 
-            return;
+// Double or halve the step size until a step takes about the target time
+fn calibrate(size: usize, elapsed: Duration) -> usize {
+    let target = target_step_time();
+    if elapsed < target / 2 {
+        size.saturating_mul(2)
+    } else if elapsed > target * 2 {
+        (size / 2).max(1)
+    } else {
+        size
+    }
+}
+
+// Like timed_step, but we always need the time to calibrate the step size
+fn measured_step(step: impl FnOnce()) -> Duration {
+    let start = Instant::now();
+    step();
+    let elapsed = start.elapsed();
+    if statistics::enabled() {
+        statistics::record_step(elapsed);
+    }
+    elapsed
+}
+
+fn timed_step<R>(step: impl FnOnce() -> R) -> R {
+    if statistics::enabled() {
+        statistics::sampled_step(step)
+    } else {
+        step()
+    }
+}
+
+#[test]
+fn test_output_task() {
+    use crate::rayon::get_thread_pool;
+    // works on a range of numbers but outputs the primes in it
+    struct Primes {
+        range: std::ops::Range<u64>,
+        primes: Vec<u64>,
+    }
+    impl OutputTask for Primes {
+        type Output = Vec<u64>;
+        fn step(&mut self) {
+            let n = self.range.start;
+            if n > 1 && (2..n).take_while(|d| d * d <= n).all(|d| n % d != 0) {
+                self.primes.push(n);
+            }
+            self.range.start += 1;
+        }
+        fn can_split(&self) -> bool {
+            self.range.end - self.range.start > 1
+        }
+        fn is_finished(&self) -> bool {
+            self.range.start == self.range.end
+        }
+        fn split(&mut self, steal_counter: usize) -> Vec<Self> {
+            let step = (self.range.end - self.range.start) / (steal_counter as u64 + 1) + 1;
+            let end = self.range.end;
+            self.range.end = self.range.start + step;
+            (self.range.end..end)
+                .step_by(step as usize)
+                .map(|start| Primes {
+                    range: start..(start + step).min(end),
+                    primes: Vec::new(),
+                })
+                .collect()
+        }
+        fn output(self) -> Vec<u64> {
+            self.primes
+        }
+        fn reduce(mut left: Vec<u64>, mut right: Vec<u64>) -> Vec<u64> {
+            left.append(&mut right);
+            left
         }
-        self.split_run(steal_counter);
     }
+    let pool = get_thread_pool();
+    let primes = pool.install(|| {
+        Primes {
+            range: 0..100_000,
+            primes: Vec::new(),
+        }
+        .run()
+    });
+    assert_eq!(primes.len(), 9592);
+    assert!(primes.windows(2).all(|w| w[0] < w[1]));
+}
 
-    fn split_run(&mut self, steal_counter: usize) {
-        #[cfg(feature = "statistics")]
-        SUCCESSFUL_STEALS.fetch_add(1, Relaxed);
-        #[cfg(feature = "statistics")]
-        TOTAL_STEAL_COUNTER.fetch_add(steal_counter, Relaxed);
+#[cfg(test)]
+// Sums up a range of numbers and fails (or panics) on one of them
+struct Failing {
+    range: std::ops::Range<u64>,
+    sum: u64,
+    bad: u64,
+    panic: bool,
+    cancel: CancellationToken,
+}
+#[cfg(test)]
+impl Failing {
+    fn check(&mut self) -> Result<(), u64> {
+        let n = self.range.start;
+        if n == self.bad {
+            assert!(!self.panic, "found {}", n);
+            return Err(n);
+        }
+        self.sum += n;
+        self.range.start += 1;
+        Ok(())
+    }
+    fn split_range(&mut self, mut runner: impl FnMut(&mut Vec<&mut Self>)) {
+        let half = (self.range.end - self.range.start) / 2 + self.range.start;
+        let mut other = Failing {
+            range: half..self.range.end,
+            sum: 0,
+            bad: self.bad,
+            panic: self.panic,
+            cancel: self.cancel.clone(),
+        };
+        self.range.end = half;
+        runner(&mut vec![self, &mut other]);
+    }
+}
+#[cfg(test)]
+impl TryTask for Failing {
+    type Error = u64;
+    fn try_step(&mut self) -> Result<(), u64> {
+        self.check()
+    }
+    fn can_split(&self) -> bool {
+        self.range.end - self.range.start > 1
+    }
+    fn is_finished(&self) -> bool {
+        self.range.start == self.range.end
+    }
+    fn cancellation(&self) -> Option<&CancellationToken> {
+        Some(&self.cancel)
+    }
+    fn split(&mut self, runner: impl FnMut(&mut Vec<&mut Self>), _steal_counter: usize) {
+        self.split_range(runner);
+    }
+    fn fuse(&mut self, other: &mut Self) {
+        self.sum += other.sum;
+    }
+}
+#[cfg(test)]
+impl Task for Failing {
+    fn step(&mut self) {
+        self.check().unwrap();
+    }
+    fn can_split(&self) -> bool {
+        self.range.end - self.range.start > 1
+    }
+    fn is_finished(&self) -> bool {
+        self.range.start == self.range.end
+    }
+    fn split(&mut self, runner: impl FnMut(&mut Vec<&mut Self>), _steal_counter: usize) {
+        self.split_range(runner);
+    }
+    fn fuse(&mut self, other: &mut Self) {
+        self.sum += other.sum;
+    }
+}
 
-        #[cfg(feature = "multisplit")]
-        self.split(Self::runner, steal_counter);
-        #[cfg(not(feature = "multisplit"))]
-        self.split(Self::runner, 1);
+#[test]
+fn test_failing_tasks() {
+    use crate::rayon::get_thread_pool;
+    let pool = get_thread_pool();
+    let failing = |bad, panic| Failing {
+        range: 0..1_000_000,
+        sum: 0,
+        bad,
+        panic,
+        cancel: CancellationToken::new(),
+    };
+    let mut ok = failing(std::u64::MAX, false);
+    assert_eq!(pool.install(|| ok.try_run()), Ok(()));
+    assert_eq!(ok.sum, 999_999 * 1_000_000 / 2);
+    let mut error = failing(765_432, false);
+    assert_eq!(pool.install(|| error.try_run()), Err(765_432));
+    // the panic needs to come through, wherever the piece with the bad number ran
+    for bad in vec![0, 500_000, 999_999] {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut task = failing(bad, true);
+            pool.install(|| task.run());
+        }));
+        assert!(result.is_err());
     }
+    // and the pool is still fine afterwards
+    assert_eq!(pool.install(|| steal::get_my_steal_count()), 0);
+    let mut ok = failing(std::u64::MAX, false);
+    pool.install(|| ok.run());
+    assert_eq!(ok.sum, 999_999 * 1_000_000 / 2);
+}
 
-    fn can_split(&self) -> bool;
-    fn work(&self) -> Option<(&'static str, usize)> {
-        None
+#[test]
+fn test_calibrate() {
+    let target = target_step_time();
+    assert_eq!(calibrate(8, Duration::from_nanos(0)), 16);
+    assert_eq!(calibrate(8, target), 8);
+    assert_eq!(calibrate(8, target * 4), 4);
+    assert_eq!(calibrate(1, target * 4), 1);
+}
+
+#[test]
+fn test_nested_tasks() {
+    // counts the pairs i < j of a range, an element processed twice counts its pairs twice
+    struct Pairs {
+        range: std::ops::Range<u64>,
+        end: u64,
+        count: u64,
+    }
+    struct Count {
+        range: std::ops::Range<u64>,
+        count: u64,
+    }
+    impl Task for Pairs {
+        fn step(&mut self) {
+            self.step_nested(&mut ());
+        }
+        fn step_nested(&mut self, outer: &mut dyn Enclosing) {
+            let i = self.range.start;
+            self.range.start += 1;
+            let mut inner = Count {
+                range: i + 1..self.end,
+                count: 0,
+            };
+            inner.run_nested(&mut Nest::new(self, outer));
+            self.count += inner.count;
+        }
+        fn can_split(&self) -> bool {
+            self.range.end - self.range.start > 1
+        }
+        fn is_finished(&self) -> bool {
+            self.range.start == self.range.end
+        }
+        fn split(&mut self, mut runner: impl FnMut(&mut Vec<&mut Self>), _steal_counter: usize) {
+            let half = (self.range.end - self.range.start) / 2 + self.range.start;
+            let mut other = Pairs {
+                range: half..self.range.end,
+                end: self.end,
+                count: 0,
+            };
+            self.range.end = half;
+            runner(&mut vec![self, &mut other]);
+        }
+        fn fuse(&mut self, other: &mut Self) {
+            self.count += other.count;
+        }
+    }
+    impl Task for Count {
+        fn step(&mut self) {
+            let end = (self.range.start + 10).min(self.range.end);
+            self.count += end - self.range.start;
+            self.range.start = end;
+        }
+        fn can_split(&self) -> bool {
+            self.range.end - self.range.start > 100
+        }
+        fn is_finished(&self) -> bool {
+            self.range.start == self.range.end
+        }
+        fn split(&mut self, mut runner: impl FnMut(&mut Vec<&mut Self>), _steal_counter: usize) {
+            let half = (self.range.end - self.range.start) / 2 + self.range.start;
+            let mut other = Count {
+                range: half..self.range.end,
+                count: 0,
+            };
+            self.range.end = half;
+            runner(&mut vec![self, &mut other]);
+        }
+        fn fuse(&mut self, other: &mut Self) {
+            self.count += other.count;
+        }
+    }
+    let n = 3000;
+    let pool = crate::rayon::get_builtin_thread_pool(4, 8);
+    for _ in 0..5 {
+        let mut pairs = Pairs {
+            range: 0..n,
+            end: n,
+            count: 0,
+        };
+        pool.install(|| pairs.run());
+        assert_eq!(pairs.count, n * (n - 1) / 2);
     }
-    fn is_finished(&self) -> bool;
-    fn split(&mut self, runner: impl FnMut(&mut Vec<&mut Self>), steal_counter: usize);
-    fn fuse(&mut self, other: &mut Self);
 }
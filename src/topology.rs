@@ -0,0 +1,141 @@
+//! Which threads of a pool are close to each other, e.g. on the same socket or sharing an L3
+//! cache. Stealing from a thread in another group means moving the data over, so thieves
+//! don't wait as long for remote victims and then rather ask a closer one.
+use std::fs;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topology {
+    // the group of every thread
+    groups: Vec<usize>,
+}
+
+impl Topology {
+    /// `groups[i]` is the group of thread i.
+    pub fn from_groups(groups: Vec<usize>) -> Self {
+        Topology { groups }
+    }
+    /// Everything is close to everything.
+    pub fn flat(num_threads: usize) -> Self {
+        Topology::from_groups(vec![0; num_threads])
+    }
+    /// Threads 0..group_size are a group, then group_size..2*group_size and so on.
+    pub fn uniform(num_threads: usize, group_size: usize) -> Self {
+        assert!(group_size > 0);
+        Topology::from_groups((0..num_threads).map(|i| i / group_size).collect())
+    }
+    /// Groups the threads by L3 cache (or by socket if we don't know about the caches), read
+    /// from /sys. Rayon doesn't pin threads, so we just assume thread i runs on cpu i (modulo
+    /// the number of cpus). Without /sys everything ends up in one group.
+    pub fn detect(num_threads: usize) -> Self {
+        let cpus = num_cpus::get();
+        let keys = (0..cpus)
+            .map(|cpu| {
+                let read = |file: &str| {
+                    fs::read_to_string(format!("/sys/devices/system/cpu/cpu{}/{}", cpu, file))
+                        .ok()
+                        .map(|s| s.trim().to_string())
+                };
+                let socket = read("topology/physical_package_id");
+                let cache = read("cache/index3/id");
+                (socket, cache)
+            })
+            .collect::<Vec<_>>();
+        // number the groups in the order we see them
+        let mut seen = Vec::new();
+        let cpu_groups = keys
+            .iter()
+            .map(|key| {
+                seen.iter().position(|k| k == key).unwrap_or_else(|| {
+                    seen.push(key.clone());
+                    seen.len() - 1
+                })
+            })
+            .collect::<Vec<_>>();
+        Topology::from_groups((0..num_threads).map(|i| cpu_groups[i % cpus]).collect())
+    }
+    pub fn num_threads(&self) -> usize {
+        self.groups.len()
+    }
+    pub fn group(&self, thread: usize) -> Option<usize> {
+        self.groups.get(thread).cloned()
+    }
+    /// Are the two threads in the same group? Threads we don't know about are far away.
+    pub fn is_local(&self, a: usize, b: usize) -> bool {
+        match (self.group(a), self.group(b)) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        }
+    }
+    /// Some other thread in the group of `thread`, if it isn't alone.
+    pub fn random_neighbour(&self, thread: usize) -> Option<usize> {
+        let group = self.group(thread)?;
+        let neighbours = (0..self.num_threads())
+            .filter(|&other| other != thread && self.groups[other] == group)
+            .collect::<Vec<_>>();
+        if neighbours.is_empty() {
+            None
+        } else {
+            Some(neighbours[rand::random::<usize>() % neighbours.len()])
+        }
+    }
+}
+
+#[test]
+fn test_topology() {
+    let topology = Topology::uniform(8, 4);
+    assert!(topology.is_local(0, 3));
+    assert!(!topology.is_local(3, 4));
+    assert!(!topology.is_local(7, 8));
+    assert!(Topology::flat(3).is_local(0, 2));
+    let detected = Topology::detect(2 * num_cpus::get());
+    assert_eq!(detected.num_threads(), 2 * num_cpus::get());
+    // thread i and i + cpus are assumed to run on the same cpu
+    assert!(detected.is_local(0, num_cpus::get()));
+
+    let mut neighbours = std::collections::HashSet::new();
+    for _ in 0..100 {
+        neighbours.insert(topology.random_neighbour(5).unwrap());
+    }
+    assert_eq!(neighbours, [4, 6, 7].iter().cloned().collect());
+    assert_eq!(Topology::uniform(3, 2).random_neighbour(2), None);
+
+    // nobody answers in a pool without steal requests, so every request runs into the timeout
+    use crate::rayon::{current_thread_index, AdaptivePoolBuilder};
+    use crate::statistics::measure;
+    use crate::steal::{steal_with_topology, StealStrategy};
+    use std::time::{Duration, Instant};
+    let pool = AdaptivePoolBuilder::new()
+        .num_threads(4)
+        .without_steal_requests()
+        .statistics(true)
+        .builtin()
+        .build();
+    let wait = Duration::from_millis(40);
+    let strategy = StealStrategy::Timed(wait);
+    let ((local, remote, alone), stats) = pool.install(|| {
+        let thief = current_thread_index().unwrap();
+        let topology = Topology::uniform(4, 2);
+        // nobody else in our group, so there's no neighbour to ask
+        let lonely = Topology::from_groups((0..4).map(|i| (i != thief) as usize).collect());
+        let timed = |topology: &Topology, victim| {
+            let start = Instant::now();
+            assert_eq!(steal_with_topology(strategy, topology, victim), None);
+            start.elapsed()
+        };
+        measure(|| {
+            (
+                timed(&topology, thief ^ 1),
+                timed(&topology, (thief + 2) % 4),
+                timed(&lonely, (thief + 2) % 4),
+            )
+        })
+    });
+    // remote victims get a quarter of the wait, then a neighbour gets all of it
+    assert!(local >= wait);
+    assert!(alone >= wait / 4 && alone < wait);
+    assert!(remote >= wait + wait / 4);
+    let stats = stats.total();
+    assert_eq!((stats.steal_successes, stats.steal_failures), (0, 3));
+    assert_eq!(stats.remote_steal_failures, 2);
+    assert_eq!(stats.neighbour_steal_failures, 1);
+}